@@ -5,7 +5,6 @@
 #![feature(asm)]
 #![feature(start)]
 
-mod elf;
 use elf::*;
 
 #[allow(dead_code)]
@@ -57,7 +56,7 @@ pub unsafe extern "C" fn bootmain() {
     let eph_ptr = ph_ptr.offset(elf.e_phnum as isize) as *const proghdr;
     while ph_ptr < eph_ptr {
         let ph = ph_ptr.as_ref().unwrap();
-        let pa = ph.p_paddr;
+        let pa = ph.p_paddr as *mut u8;
         readseg(pa, ph.p_filesz, ph.p_offset);
         if ph.p_memsz > ph.p_filesz {
             stosb(pa.offset(ph.p_filesz as isize), 0, ph.p_memsz - ph.p_filesz);
@@ -69,7 +68,8 @@ pub unsafe extern "C" fn bootmain() {
 
     // Call the entry point from the ELF header.
     // Does not return !
-    (elf.e_entry)();
+    let entry: extern "C" fn() = core::mem::transmute(elf.e_entry);
+    entry();
 }
 
 const SECTSIZE: usize = 512; // same as u32 on i386
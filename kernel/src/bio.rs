@@ -0,0 +1,276 @@
+// Buffer cache.
+//
+// The buffer cache is a linked list of buf structures holding
+// cached copies of disk block contents.  Caching disk blocks
+// in memory reduces the number of disk reads and also provides
+// a synchronization point for disk blocks used by multiple processes.
+//
+// Interface:
+// * To get a buffer for a particular disk block, call bread.
+// * After changing buffer data, call bwrite to write it to disk.
+// * When done with the buffer, drop the guard (or call brelse).
+// * Do not use the buffer after dropping the guard.
+// * Only one process at a time can use a buffer,
+//     so do not keep them longer than necessary.
+//
+// The implementation uses two state flags internally:
+// * VALID: the buffer data has been read from the disk.
+// * DIRTY: the buffer data has been modified
+//     and needs to be written to disk.
+
+use core::ops::{Deref, DerefMut};
+
+use super::buf::{Buf, BufFlags};
+use super::param::NBUF;
+use super::sleeplock::{SleepLock, SleepLockGuard};
+use super::spinlock::SpinLock;
+
+// Something that can read and write disk blocks.
+// The IDE driver is the only real one; a RAM-backed
+// device can stand in for it.
+pub trait BlockDevice: Sync {
+    // Sync b with the device: write it if DIRTY is set,
+    // otherwise read it.  On return b is VALID and not DIRTY.
+    fn rw(&self, b: &mut Buf);
+}
+
+// Bookkeeping for one buffer, protected by bcache's spinlock.
+#[derive(Clone, Copy)]
+struct BufMeta {
+    dev: u32,
+    blockno: u32,
+    refcnt: u32,
+    dirty: bool, // DIRTY as of the last release
+    prev: usize, // LRU cache list
+    next: usize,
+}
+
+impl BufMeta {
+    const fn new() -> Self {
+        BufMeta {
+            dev: 0,
+            blockno: 0,
+            refcnt: 0,
+            dirty: false,
+            prev: 0,
+            next: 0,
+        }
+    }
+}
+
+// Linked list of all buffers, through prev/next.
+// meta[HEAD] is the list head; head.next is most recently used.
+const HEAD: usize = NBUF;
+
+struct Bcache {
+    meta: [BufMeta; NBUF + 1],
+    device: Option<&'static dyn BlockDevice>,
+}
+
+impl Bcache {
+    // Unlink buffer i from the list.
+    fn unlink(&mut self, i: usize) {
+        let (prev, next) = (self.meta[i].prev, self.meta[i].next);
+        self.meta[next].prev = prev;
+        self.meta[prev].next = next;
+    }
+
+    // Insert buffer i at the head of the list.
+    fn push_front(&mut self, i: usize) {
+        let next = self.meta[HEAD].next;
+        self.meta[i].next = next;
+        self.meta[i].prev = HEAD;
+        self.meta[next].prev = i;
+        self.meta[HEAD].next = i;
+    }
+}
+
+static bcache: SpinLock<Bcache> = SpinLock::new("bcache", {
+    const INIT: BufMeta = BufMeta::new();
+    Bcache {
+        meta: [INIT; NBUF + 1],
+        device: None,
+    }
+});
+
+static bufs: [SleepLock<Buf>; NBUF] = {
+    const INIT: SleepLock<Buf> = SleepLock::new("buffer", Buf::new());
+    [INIT; NBUF]
+};
+
+// A locked buffer.  The buffer is released when the guard is dropped.
+pub struct BufGuard {
+    idx: usize,
+    buf: Option<SleepLockGuard<'static, Buf>>,
+}
+
+pub fn binit(device: &'static dyn BlockDevice) {
+    let mut bc = bcache.lock();
+    bc.device = Some(device);
+
+    // Create linked list of buffers
+    bc.meta[HEAD].prev = HEAD;
+    bc.meta[HEAD].next = HEAD;
+    for i in 0..NBUF {
+        bc.push_front(i);
+    }
+}
+
+// Look through buffer cache for block on device dev.
+// If not found, allocate a buffer.
+// In either case, return locked buffer.
+fn bget(dev: u32, blockno: u32) -> BufGuard {
+    let mut bc = bcache.lock();
+
+    // Is the block already cached?
+    let mut i = bc.meta[HEAD].next;
+    while i != HEAD {
+        let m = &mut bc.meta[i];
+        if m.dev == dev && m.blockno == blockno {
+            m.refcnt += 1;
+            drop(bc);
+            return BufGuard {
+                idx: i,
+                buf: Some(bufs[i].lock()),
+            };
+        }
+        i = m.next;
+    }
+
+    // Not cached; recycle an unused buffer.
+    // Even if refcnt==0, DIRTY indicates a buffer is in use
+    // because log.rs has modified it but not yet committed it.
+    let mut i = bc.meta[HEAD].prev;
+    while i != HEAD {
+        let m = &mut bc.meta[i];
+        if m.refcnt == 0 && !m.dirty {
+            m.dev = dev;
+            m.blockno = blockno;
+            m.refcnt = 1;
+            drop(bc);
+            let mut b = bufs[i].lock();
+            b.dev = dev;
+            b.blockno = blockno;
+            b.flags = BufFlags::empty();
+            return BufGuard {
+                idx: i,
+                buf: Some(b),
+            };
+        }
+        i = m.prev;
+    }
+    panic!("bget: no buffers");
+}
+
+fn device() -> &'static dyn BlockDevice {
+    bcache.lock().device.expect("bio: no block device")
+}
+
+// Return a locked buf with the contents of the indicated block.
+pub fn bread(dev: u32, blockno: u32) -> BufGuard {
+    let mut b = bget(dev, blockno);
+    if !b.flags.contains(BufFlags::VALID) {
+        device().rw(&mut b);
+    }
+    b
+}
+
+// Write b's contents to disk.
+pub fn bwrite(b: &mut BufGuard) {
+    b.flags.insert(BufFlags::DIRTY);
+    device().rw(b);
+}
+
+// Release a locked buffer.
+// Move to the head of the MRU list.
+pub fn brelse(b: BufGuard) {
+    drop(b);
+}
+
+impl Deref for BufGuard {
+    type Target = Buf;
+    fn deref(&self) -> &Buf {
+        self.buf.as_ref().unwrap()
+    }
+}
+impl DerefMut for BufGuard {
+    fn deref_mut(&mut self) -> &mut Buf {
+        self.buf.as_mut().unwrap()
+    }
+}
+impl Drop for BufGuard {
+    fn drop(&mut self) {
+        // Update the bookkeeping before giving up the sleeplock,
+        // so that the next holder can't log_write() the buffer
+        // in between and have its DIRTY overwritten here.
+        let mut bc = bcache.lock();
+        let i = self.idx;
+        bc.meta[i].dirty = self.flags.contains(BufFlags::DIRTY);
+        bc.meta[i].refcnt -= 1;
+        if bc.meta[i].refcnt == 0 {
+            // no one is waiting for it.
+            bc.unlink(i);
+            bc.push_front(i);
+        }
+        drop(self.buf.take());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::param::ROOTDEV;
+    use crate::ramdisk::RAMDISK;
+    use crate::utils;
+
+    // These tests use blocks 60 and up; log.rs's use the ones below.
+
+    #[test_case]
+    fn bread_hits_cache() {
+        {
+            let mut b = bread(ROOTDEV, 60);
+            utils::fill(&mut b.data, 0x11);
+            bwrite(&mut b);
+        }
+        assert!(RAMDISK.read(60).iter().all(|c| *c == 0x11));
+
+        let (reads, _) = RAMDISK.stats();
+        let b = bread(ROOTDEV, 60);
+        assert!(b.data.iter().all(|c| *c == 0x11));
+        assert_eq!(RAMDISK.stats().0, reads);
+    }
+
+    #[test_case]
+    fn brelse_recycles_least_recently_used() {
+        drop(bread(ROOTDEV, 61));
+        // Now 61 is the least recently used buffer, so
+        // reading NBUF other blocks must recycle it.
+        for i in 0..NBUF as u32 {
+            drop(bread(ROOTDEV, 100 + i));
+        }
+        let (reads, _) = RAMDISK.stats();
+        drop(bread(ROOTDEV, 61));
+        assert_eq!(RAMDISK.stats().0, reads + 1);
+    }
+
+    #[test_case]
+    fn dirty_buffers_are_not_recycled() {
+        // Like log_write(): modify the buffer and mark it DIRTY
+        // without writing it.
+        {
+            let mut b = bread(ROOTDEV, 62);
+            utils::fill(&mut b.data, 0x22);
+            b.flags.insert(BufFlags::DIRTY);
+        }
+        // Every other buffer gets recycled...
+        for i in 0..NBUF as u32 - 1 {
+            drop(bread(ROOTDEV, 200 + i));
+        }
+        // ...but 62 must still be cached, with its changes.
+        let (reads, _) = RAMDISK.stats();
+        let mut b = bread(ROOTDEV, 62);
+        assert_eq!(RAMDISK.stats().0, reads);
+        assert!(b.data.iter().all(|c| *c == 0x22));
+        bwrite(&mut b);
+    }
+}
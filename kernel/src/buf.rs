@@ -0,0 +1,28 @@
+use super::fs::BSIZE;
+
+bitflags! {
+    pub struct BufFlags: u32 {
+        const VALID = 0x2; // buffer has been read from disk
+        const DIRTY = 0x4; // buffer needs to be written to disk
+    }
+}
+
+pub struct Buf {
+    pub flags: BufFlags,
+    pub dev: u32,
+    pub blockno: u32,
+    pub qnext: *mut Buf, // disk queue
+    pub data: [u8; BSIZE],
+}
+
+impl Buf {
+    pub const fn new() -> Self {
+        Buf {
+            flags: BufFlags::empty(),
+            dev: 0,
+            blockno: 0,
+            qnext: core::ptr::null_mut(),
+            data: [0; BSIZE],
+        }
+    }
+}
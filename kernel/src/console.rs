@@ -1,14 +1,16 @@
 use super::file;
+use super::heap;
 use super::ioapic;
+use super::proc;
+use super::spinlock::SpinLock;
 use super::traps;
 use super::uart;
 use super::utils::address::{p2v, paddr, vaddr};
+use super::vga_buffer::VGA_WRITER;
 use super::x86;
 
-use spin::Mutex;
-
-const NDEV: usize = 10; // maximum major device number
 const INPUT_BUF: usize = 128;
+const BACKSPACE: u16 = 0x100;
 
 struct InputBuffer {
     buf: [u8; INPUT_BUF],
@@ -17,7 +19,7 @@ struct InputBuffer {
     e: usize, // edit index
 }
 impl InputBuffer {
-    fn new() -> Self {
+    const fn new() -> Self {
         InputBuffer {
             buf: [0; INPUT_BUF],
             r: 0,
@@ -32,22 +34,22 @@ fn C(x: u8) -> u8 {
     x - b'@'
 }
 
-lazy_static! {
-    static ref devsw: Mutex<[file::devsw; NDEV]> = Mutex::new([file::devsw::new(); NDEV]);
-    static ref cons: Mutex<InputBuffer> = Mutex::new(InputBuffer::new());
-}
+static cons: SpinLock<InputBuffer> = SpinLock::new("cons", InputBuffer::new());
 static mut panicked: bool = false;
 
 fn cgaputc(c: u16) {
-    print!("{}", core::char::from_u32(c as u32).unwrap());
+    if c == BACKSPACE {
+        VGA_WRITER.lock().backspace();
+    } else {
+        print!("{}", core::char::from_u32(c as u32).unwrap());
+    }
 }
 
 fn putc(c: u16) {
     if unsafe { panicked } {
         loop {}
     }
-    // BACKSPACE
-    if c == 0x100 {
+    if c == BACKSPACE {
         uart::putc(0x08); // BS
         uart::putc(0x20); // SPC
         uart::putc(0x08); // BS
@@ -61,31 +63,113 @@ fn panic(s: &str) {
     panic!("{}", s)
 }
 
-fn console_read(inode: *const file::Inode, n: usize) -> *const [u8] {
-    let _inode_content = unsafe { (*inode).content.lock() };
-    let target = n;
+// Readers sleep on the address of the read index.
+fn input_chan() -> vaddr {
+    vaddr::from_ptr(unsafe { &(*cons.as_mut_ptr()).r } as *const usize).unwrap()
+}
+
+// Feed input characters from getc into the line buffer,
+// handling the line-editing keys.
+// Called by the keyboard and serial port interrupt handlers.
+pub fn consoleintr(getc: fn() -> Option<u8>) {
+    let mut doprocdump = false;
     {
-        let _cons_lock = cons.lock();
-        while n > 0 {
-            unimplemented!()
+        let mut input = cons.lock();
+        while let Some(c) = getc() {
+            match c {
+                c if c == C(b'P') => {
+                    // Process listing.
+                    // procdump() locks cons indirectly; invoke later
+                    doprocdump = true;
+                }
+                c if c == C(b'U') => {
+                    // Kill line.
+                    while input.e != input.w
+                        && input.buf[input.e.wrapping_sub(1) % INPUT_BUF] != b'\n'
+                    {
+                        input.e = input.e.wrapping_sub(1);
+                        putc(BACKSPACE);
+                    }
+                }
+                c if c == C(b'H') || c == 0x7f => {
+                    // Backspace
+                    if input.e != input.w {
+                        input.e = input.e.wrapping_sub(1);
+                        putc(BACKSPACE);
+                    }
+                }
+                _ => {
+                    if c != 0 && input.e.wrapping_sub(input.r) < INPUT_BUF {
+                        let c = if c == b'\r' { b'\n' } else { c };
+                        let e = input.e;
+                        input.buf[e % INPUT_BUF] = c;
+                        input.e = e.wrapping_add(1);
+                        putc(c as u16);
+                        if c == b'\n' || c == C(b'D') || input.e == input.r.wrapping_add(INPUT_BUF)
+                        {
+                            input.w = input.e;
+                            proc::wakeup(input_chan());
+                        }
+                    }
+                }
+            }
+        }
+    }
+    if doprocdump {
+        proc::procdump(); // now call procdump() wo. cons lock held
+        heap::heapdump();
+    }
+}
+
+// Read up to one line of console input.
+// Blocks until a whole line (or ^D) has been typed.
+fn console_read(dst: &mut [u8]) -> Result<usize, &'static str> {
+    let target = dst.len();
+    let mut n = 0;
+    let mut input = cons.lock();
+    while n < target {
+        while input.r == input.w {
+            if proc::myproc().map_or(true, |p| p.killed) {
+                return Err("console_read: killed");
+            }
+            input = proc::sleep(input_chan(), input);
+        }
+        let c = input.buf[input.r % INPUT_BUF];
+        input.r = input.r.wrapping_add(1);
+        if c == C(b'D') {
+            // EOF
+            if n > 0 {
+                // Save ^D for next time, to make sure
+                // caller gets a 0-byte result.
+                input.r = input.r.wrapping_sub(1);
+            }
+            break;
+        }
+        dst[n] = c;
+        n += 1;
+        if c == b'\n' {
+            break;
         }
     }
-    unimplemented!()
+    Ok(n)
 }
-fn console_write(inode: *const file::Inode, bytes: *const [u8]) {
-    let _inode_content = unsafe { (*inode).content.lock() };
+fn console_write(src: &[u8]) -> Result<usize, &'static str> {
     {
         let _cons_lock = cons.lock();
-        for c in unsafe { (*bytes).iter() } {
+        for c in src.iter() {
             putc(*c as u16);
         }
     }
+    Ok(src.len())
 }
 
 pub fn console_init() {
-    let mut tmp = devsw.lock();
-    tmp[file::CONSOLE].write = Some(console_write);
-    tmp[file::CONSOLE].read = Some(console_read);
+    file::devsw.lock().insert(
+        file::CONSOLE,
+        file::DevSw {
+            read: Some(console_read),
+            write: Some(console_write),
+        },
+    );
     ioapic::ioapic_enable(traps::IRQ_KBD, 0);
-    // unimplemented!(); // TODO implement console_read
 }
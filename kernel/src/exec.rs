@@ -0,0 +1,165 @@
+use elf::{elfhdr, proghdr, ELF_MAGIC, ELF_PROG_FLAG_WRITE, ELF_PROG_LOAD};
+
+use super::fs::{self, InodeGuard};
+use super::log;
+use super::mmap;
+use super::mmu::{self, PteFlags, PGSIZE};
+use super::param::MAXARG;
+use super::proc;
+use super::utils::address::vaddr_raw;
+use super::utils::common::mut_bytes_from_ref;
+use super::vm::{self, PageDir};
+
+// Replace the current process's image with the program at path.
+// The new image is built in a fresh page directory, and the old one
+// is only released once everything has succeeded, so on failure
+// the caller's image is left untouched.
+pub fn exec(path: &[u8], argv: &[&[u8]]) -> Result<usize, &'static str> {
+    let curproc = proc::myproc().expect("exec: no process");
+
+    let pgdir = vm::setupkvm().ok_or("exec: out of memory")?;
+    let (sz, entry) = match load(pgdir, path) {
+        Ok(r) => r,
+        Err(e) => {
+            vm::freevm(pgdir);
+            return Err(e);
+        }
+    };
+    let (sz, sp) = match setupstack(pgdir, sz, argv) {
+        Ok(r) => r,
+        Err(e) => {
+            vm::freevm(pgdir);
+            return Err(e);
+        }
+    };
+
+    // Save program name for debugging.
+    let last = path.rsplit(|c| *c == b'/').next().unwrap_or(path);
+    curproc.set_name(last);
+
+    // Commit to the user image.
+    mmap::unmapall(curproc);
+    let oldpgdir = curproc.pgdir.replace(pgdir);
+    curproc.sz = sz;
+    curproc.guard = sz - 2 * PGSIZE; // just below the stack page
+    let tf = unsafe { &mut *curproc.tf };
+    tf.eip = entry as u32; // main
+    tf.esp = sp as u32;
+    vm::switchuvm(curproc);
+    if let Some(oldpgdir) = oldpgdir {
+        vm::freevm(oldpgdir);
+    }
+    Ok(0)
+}
+
+// Load the ELF executable at path into pgdir.
+// Returns the size of the loaded image and its entry point.
+fn load(pgdir: &mut PageDir, path: &[u8]) -> Result<(usize, usize), &'static str> {
+    log::begin_op();
+    let r = fs::namei(path).ok_or("exec: no such file").and_then(|ip| {
+        let mut ip = ip.ilock();
+        loadelf(pgdir, &mut ip)
+    });
+    log::end_op();
+    r
+}
+
+fn loadelf(pgdir: &mut PageDir, ip: &mut InodeGuard) -> Result<(usize, usize), &'static str> {
+    // Check ELF header
+    let mut elf = elfhdr::default();
+    if ip.readi(mut_bytes_from_ref(&mut elf), 0)? != core::mem::size_of::<elfhdr>() {
+        return Err("exec: short ELF header");
+    }
+    if &elf.e_ident[0..4] != &ELF_MAGIC[..] {
+        return Err("exec: bad ELF magic");
+    }
+
+    // Load program into memory.
+    let mut sz = 0;
+    for i in 0..elf.e_phnum as usize {
+        let off = i
+            .checked_mul(core::mem::size_of::<proghdr>())
+            .and_then(|o| o.checked_add(elf.e_phoff))
+            .ok_or("exec: bad program header offset")?;
+        let mut ph = proghdr::default();
+        if ip.readi(mut_bytes_from_ref(&mut ph), off)? != core::mem::size_of::<proghdr>() {
+            return Err("exec: short program header");
+        }
+        if ph.p_type != ELF_PROG_LOAD {
+            continue;
+        }
+        if ph.p_memsz < ph.p_filesz {
+            return Err("exec: p_memsz < p_filesz");
+        }
+        let end = ph
+            .p_vaddr
+            .checked_add(ph.p_memsz)
+            .ok_or("exec: segment overflows address space")?;
+        if ph.p_vaddr % PGSIZE != 0 {
+            return Err("exec: segment not page aligned");
+        }
+        let mut perm = PteFlags::USER;
+        if ph.p_flags & ELF_PROG_FLAG_WRITE != 0 {
+            perm |= PteFlags::WRITABLE;
+        }
+        sz = vm::allocuvm(pgdir, sz, end, perm).ok_or("exec: out of memory")?;
+        vm::loaduvm(pgdir, ph.p_vaddr, ip, ph.p_offset, ph.p_filesz)?;
+    }
+    Ok((sz, elf.e_entry))
+}
+
+// Allocate a guard page and a user stack above the image of size sz,
+// and push the argument strings and argv[] onto the stack.
+// Returns the new image size and the initial stack pointer.
+fn setupstack(
+    pgdir: &mut PageDir,
+    sz: usize,
+    argv: &[&[u8]],
+) -> Result<(usize, usize), &'static str> {
+    if argv.len() > MAXARG {
+        return Err("exec: too many arguments");
+    }
+
+    // Allocate two pages at the next page boundary.
+    // Make the first inaccessible.  Use the second as the user stack.
+    let sz = mmu::page_roundup(vaddr_raw(sz)).as_raw();
+    let sz = vm::allocuvm(
+        pgdir,
+        sz,
+        sz + 2 * PGSIZE,
+        PteFlags::WRITABLE | PteFlags::USER,
+    )
+    .ok_or("exec: out of memory")?;
+    vm::clearpteu(pgdir, sz - 2 * PGSIZE);
+    let stackbase = sz - PGSIZE;
+
+    // Push argument strings, prepare rest of stack in ustack.
+    let mut sp = sz;
+    let mut ustack = [0u32; 3 + MAXARG + 1];
+    let argc = argv.len();
+    for (i, arg) in argv.iter().enumerate() {
+        sp = sp
+            .checked_sub(arg.len() + 1)
+            .filter(|sp| *sp >= stackbase)
+            .ok_or("exec: arguments too long")?
+            & !3;
+        vm::copyout(pgdir, sp, arg)?;
+        vm::copyout(pgdir, sp + arg.len(), &[0])?;
+        ustack[3 + i] = sp as u32;
+    }
+    ustack[3 + argc] = 0;
+
+    let words = 3 + argc + 1;
+    ustack[0] = 0xffffffff; // fake return PC
+    ustack[1] = argc as u32;
+    ustack[2] = (sp - (argc + 1) * 4) as u32; // argv pointer
+
+    sp = sp
+        .checked_sub(words * 4)
+        .filter(|sp| *sp >= stackbase)
+        .ok_or("exec: arguments too long")?;
+    let bytes = unsafe { core::slice::from_raw_parts(ustack.as_ptr() as *const u8, words * 4) };
+    vm::copyout(pgdir, sp, bytes)?;
+
+    Ok((sz, sp))
+}
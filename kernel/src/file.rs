@@ -1,58 +1,268 @@
-use super::fs;
-use super::pipe;
+use alloc::collections::BTreeMap;
+use core::cell::Cell;
 
-use spin::Mutex;
+use super::fs::{self, BSIZE};
+use super::log;
+use super::param::{MAXOPBLOCKS, NFILE};
+use super::pipe::{self, PipeRef};
+use super::spinlock::SpinLock;
+use super::stat::{self, T_DEV};
 
 pub enum FileType {
     FD_NONE,
-    FD_PIPE,
-    FD_INODE,
+    FD_PIPE(PipeRef),
+    FD_INODE(fs::Inode),
 }
+
 pub struct File {
-    file_type: FileType,
-    ref_count: i32,
-    readable: u8,
-    writable: u8,
-    pipe: *const pipe::Pipe,
-    ip: *const Inode,
-    off: usize,
+    pub file_type: FileType,
+    ref_count: i32, // reference count, protected by ftable's lock
+    pub readable: bool,
+    pub writable: bool,
+    off: Cell<usize>, // FD_INODE only, protected by the inode's lock
+}
+
+impl File {
+    pub const fn new() -> Self {
+        File {
+            file_type: FileType::FD_NONE,
+            ref_count: 0,
+            readable: false,
+            writable: false,
+            off: Cell::new(0),
+        }
+    }
+}
+
+static ftable: SpinLock<[File; NFILE]> = SpinLock::new("ftable", {
+    const CLOSED: File = File::new();
+    [CLOSED; NFILE]
+});
+
+// write a few blocks at a time to avoid exceeding
+// the maximum log transaction size, including
+// i-node, indirect block, allocation blocks,
+// and 2 blocks of slop for non-aligned writes.
+pub const MAXWRITE: usize = ((MAXOPBLOCKS - 1 - 1 - 2) / 2) * BSIZE;
+
+// A reference to an open file in ftable.
+// Cloning it takes another reference (filedup);
+// dropping it releases one (fileclose).
+pub struct FileRef {
+    idx: usize,
 }
 
 // Copy of disk inode
+#[derive(Debug, Clone, Copy)]
 #[repr(C)]
 pub struct InodeContent {
-    file_type: i16,
-    major: i16,
-    minor: i16,
-    nlink: i16,
-    size: usize,
-    addrs: [usize; fs::NDIRECT + 1],
+    pub file_type: i16,                  // File type
+    pub major: i16,                      // Major device number (T_DEV only)
+    pub minor: i16,                      // Minor device number (T_DEV only)
+    pub nlink: i16,                      // Number of links to inode in file system
+    pub size: usize,                     // Size of file (bytes)
+    pub addrs: [usize; fs::NDIRECT + 1], // Data block addresses
+}
+
+impl InodeContent {
+    pub const fn new() -> Self {
+        InodeContent {
+            file_type: 0,
+            major: 0,
+            minor: 0,
+            nlink: 0,
+            size: 0,
+            addrs: [0; fs::NDIRECT + 1],
+        }
+    }
 }
 
-// in-memory copy of an inode
-pub struct Inode {
-    pub dev: u32,
-    pub inum: u32,
-    pub refcnt: i32,
-    pub valid: bool,
-    pub content: Mutex<InodeContent>,
+// Functions of a device, found in devsw by major device number.
+#[derive(Clone, Copy)]
+pub struct DevSw {
+    pub read: Option<fn(&mut [u8]) -> Result<usize, &'static str>>,
+    pub write: Option<fn(&[u8]) -> Result<usize, &'static str>>,
 }
 
-// table mapping major device number to
-// device functions
-#[derive(Debug, Clone, Copy)]
-pub struct devsw {
-    pub read: Option<fn(*const Inode, usize) -> *const [u8]>,
-    pub write: Option<fn(*const Inode, *const [u8])>,
+lazy_static! {
+    // table mapping major device number to
+    // device functions
+    pub static ref devsw: SpinLock<BTreeMap<i16, DevSw>> =
+        SpinLock::new("devsw", BTreeMap::new());
+}
+
+pub const CONSOLE: i16 = 1;
+
+fn getdevsw(major: i16) -> Option<DevSw> {
+    devsw.lock().get(&major).copied()
+}
+
+// Allocate a file structure.
+pub fn filealloc(file_type: FileType, readable: bool, writable: bool) -> Option<FileRef> {
+    let mut ft = ftable.lock();
+    let idx = match ft.iter().position(|f| f.ref_count == 0) {
+        Some(idx) => idx,
+        None => {
+            // Release the lock before file_type is dropped.
+            drop(ft);
+            return None;
+        }
+    };
+    ft[idx] = File {
+        file_type,
+        ref_count: 1,
+        readable,
+        writable,
+        off: Cell::new(0),
+    };
+    Some(FileRef { idx })
+}
+
+impl FileRef {
+    // The entry can't be reused while this reference exists,
+    // and only ref_count changes while it is open.
+    fn file(&self) -> &File {
+        unsafe { &(*ftable.as_mut_ptr())[self.idx] }
+    }
+
+    pub fn readable(&self) -> bool {
+        self.file().readable
+    }
+    pub fn writable(&self) -> bool {
+        self.file().writable
+    }
+
+    // The inode behind f, unless it is a pipe.
+    pub fn inode(&self) -> Option<&fs::Inode> {
+        match &self.file().file_type {
+            FileType::FD_INODE(ip) => Some(ip),
+            _ => None,
+        }
+    }
+
+    // Get metadata about file f.
+    pub fn filestat(&self) -> Result<stat::stat, &'static str> {
+        match &self.file().file_type {
+            FileType::FD_INODE(ip) => Ok(ip.ilock().stati()),
+            _ => Err("filestat: not an inode"),
+        }
+    }
+
+    // Read from file f.
+    pub fn fileread(&self, dst: &mut [u8]) -> Result<usize, &'static str> {
+        let f = self.file();
+        if !f.readable {
+            return Err("fileread: not readable");
+        }
+        match &f.file_type {
+            FileType::FD_PIPE(p) => p.piperead(dst),
+            FileType::FD_INODE(ip) => {
+                let mut g = ip.ilock();
+                if g.file_type == T_DEV {
+                    // Devices may sleep for a long time; don't hold the inode.
+                    let major = g.major;
+                    drop(g);
+                    let read = getdevsw(major)
+                        .and_then(|d| d.read)
+                        .ok_or("fileread: no such device")?;
+                    return read(dst);
+                }
+                let n = g.readi(dst, f.off.get())?;
+                f.off.set(f.off.get() + n);
+                Ok(n)
+            }
+            FileType::FD_NONE => panic!("fileread"),
+        }
+    }
+
+    // Write to file f.
+    pub fn filewrite(&self, src: &[u8]) -> Result<usize, &'static str> {
+        let f = self.file();
+        if !f.writable {
+            return Err("filewrite: not writable");
+        }
+        match &f.file_type {
+            FileType::FD_PIPE(p) => p.pipewrite(src),
+            FileType::FD_INODE(ip) => {
+                let g = ip.ilock();
+                if g.file_type == T_DEV {
+                    let major = g.major;
+                    drop(g);
+                    let write = getdevsw(major)
+                        .and_then(|d| d.write)
+                        .ok_or("filewrite: no such device")?;
+                    return write(src);
+                }
+                drop(g);
+
+                let n = src.len();
+                let mut i = 0;
+                while i < n {
+                    let n1 = core::cmp::min(n - i, MAXWRITE);
+
+                    log::begin_op();
+                    let r = {
+                        let mut g = ip.ilock();
+                        let r = g.writei(&src[i..i + n1], f.off.get());
+                        if let Ok(r) = r {
+                            f.off.set(f.off.get() + r);
+                        }
+                        r
+                    };
+                    log::end_op();
+
+                    match r {
+                        Ok(r) if r == n1 => i += r,
+                        Ok(_) => panic!("short filewrite"),
+                        Err(e) => return Err(e),
+                    }
+                }
+                Ok(n)
+            }
+            FileType::FD_NONE => panic!("filewrite"),
+        }
+    }
 }
 
-impl devsw {
-    pub fn new() -> Self {
-        devsw {
-            read: None,
-            write: None,
+// Increment ref count for file f.
+impl Clone for FileRef {
+    fn clone(&self) -> Self {
+        let mut ft = ftable.lock();
+        if ft[self.idx].ref_count < 1 {
+            panic!("filedup");
         }
+        ft[self.idx].ref_count += 1;
+        FileRef { idx: self.idx }
     }
 }
 
-pub const CONSOLE: usize = 1;
+// Close file f.  (Decrement ref count, close when reaches 0.)
+impl Drop for FileRef {
+    fn drop(&mut self) {
+        let (ff, writable) = {
+            let mut ft = ftable.lock();
+            let f = &mut ft[self.idx];
+            if f.ref_count < 1 {
+                panic!("fileclose");
+            }
+            f.ref_count -= 1;
+            if f.ref_count > 0 {
+                return;
+            }
+            (
+                core::mem::replace(&mut f.file_type, FileType::FD_NONE),
+                f.writable,
+            )
+        };
+
+        match ff {
+            FileType::FD_PIPE(p) => pipe::pipeclose(&p, writable),
+            FileType::FD_INODE(ip) => {
+                log::begin_op();
+                drop(ip);
+                log::end_op();
+            }
+            FileType::FD_NONE => {}
+        }
+    }
+}
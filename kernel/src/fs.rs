@@ -1 +1,720 @@
+// File system implementation.  Five layers:
+//   + Blocks: allocator for raw disk blocks.
+//   + Log: crash recovery for multi-step updates.
+//   + Files: inode allocator, reading, writing, metadata.
+//   + Directories: inode with special contents (list of other inodes!)
+//   + Names: paths like /usr/rtm/xv6/fs.rs for convenient naming.
+//
+// This file contains the low-level file system manipulation
+// routines.  The (higher-level) system call implementations
+// are in sysfile.rs.
+//
+// The on-disk layout is the one produced by xv6's mkfs:
+// [ boot block | super block | log | inode blocks |
+//                                   free bit map | data blocks ]
+
+use core::ops::{Deref, DerefMut};
+
+use super::bio::{self, BufGuard};
+use super::file::InodeContent;
+use super::log;
+use super::mmap;
+use super::param::{NINODE, ROOTDEV};
+use super::proc;
+use super::rtc;
+use super::sleeplock::{SleepLock, SleepLockGuard};
+use super::spinlock::SpinLock;
+use super::stat::{self, T_DIR};
+use super::utils::common::{bytes_from_ref, fill, mut_bytes_from_ref};
+
+pub const ROOTINO: u32 = 1; // root i-number
+pub const BSIZE: usize = 512; // block size
+
+// mkfs computes the super block and builds an initial file system. The
+// super block describes the disk layout:
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct superblock {
+    pub size: u32,       // Size of file system image (blocks)
+    pub nblocks: u32,    // Number of data blocks
+    pub ninodes: u32,    // Number of inodes.
+    pub nlog: u32,       // Number of log blocks
+    pub logstart: u32,   // Block number of first log block
+    pub inodestart: u32, // Block number of first inode block
+    pub bmapstart: u32,  // Block number of first free map block
+}
+
+impl superblock {
+    const fn new() -> Self {
+        superblock {
+            size: 0,
+            nblocks: 0,
+            ninodes: 0,
+            nlog: 0,
+            logstart: 0,
+            inodestart: 0,
+            bmapstart: 0,
+        }
+    }
+}
+
 pub const NDIRECT: usize = 12;
+pub const NINDIRECT: usize = BSIZE / core::mem::size_of::<u32>();
+pub const MAXFILE: usize = NDIRECT + NINDIRECT;
+
+// Inodes per block.
+const IPB: u32 = (BSIZE / core::mem::size_of::<InodeContent>()) as u32;
+
+// Block containing inode i
+fn iblock(i: u32, sb: &superblock) -> u32 {
+    i / IPB + sb.inodestart
+}
+
+// Bitmap bits per block
+const BPB: u32 = (BSIZE * 8) as u32;
+
+// Block of free map containing bit for block b
+fn bblock(b: u32, sb: &superblock) -> u32 {
+    b / BPB + sb.bmapstart
+}
+
+// Directory is a file containing a sequence of dirent structures.
+pub const DIRSIZ: usize = 14;
+
+#[derive(Debug, Default, Clone, Copy)]
+#[repr(C)]
+pub struct dirent {
+    pub inum: u16,
+    pub name: [u8; DIRSIZ],
+}
+
+pub const DIRENT_SIZE: usize = core::mem::size_of::<dirent>();
+
+// there should be one superblock per disk device, but we run with
+// only one device
+static sblock: SpinLock<superblock> = SpinLock::new("sb", superblock::new());
+
+pub fn getsb() -> superblock {
+    *sblock.lock()
+}
+
+// Read the super block.
+fn readsb(dev: u32) -> superblock {
+    let bp = bio::bread(dev, 1);
+    unsafe { core::ptr::read_unaligned(bp.data.as_ptr() as *const superblock) }
+}
+
+// View the data of a block as an array of T.
+fn block_as<T>(bp: &mut BufGuard) -> &mut [T] {
+    unsafe {
+        core::slice::from_raw_parts_mut(
+            bp.data.as_mut_ptr() as *mut T,
+            BSIZE / core::mem::size_of::<T>(),
+        )
+    }
+}
+
+// Zero a block.
+fn bzero(dev: u32, bno: u32) {
+    let mut bp = bio::bread(dev, bno);
+    fill(&mut bp.data, 0);
+    log::log_write(&mut bp);
+}
+
+// Blocks.
+
+// Allocate a zeroed disk block.
+fn balloc(dev: u32) -> u32 {
+    let sb = getsb();
+    let mut b = 0;
+    while b < sb.size {
+        let mut bp = bio::bread(dev, bblock(b, &sb));
+        let mut bi = 0;
+        while bi < BPB && b + bi < sb.size {
+            let m = 1 << (bi % 8);
+            let byte = &mut bp.data[(bi / 8) as usize];
+            if *byte & m == 0 {
+                // Is block free?
+                *byte |= m; // Mark block in use.
+                log::log_write(&mut bp);
+                drop(bp);
+                bzero(dev, b + bi);
+                return b + bi;
+            }
+            bi += 1;
+        }
+        b += BPB;
+    }
+    panic!("balloc: out of blocks");
+}
+
+// Free a disk block.
+fn bfree(dev: u32, b: u32) {
+    let mut bp = bio::bread(dev, bblock(b, &getsb()));
+    let bi = b % BPB;
+    let m = 1 << (bi % 8);
+    let byte = &mut bp.data[(bi / 8) as usize];
+    if *byte & m == 0 {
+        panic!("freeing free block");
+    }
+    *byte &= !m;
+    log::log_write(&mut bp);
+}
+
+// Inodes.
+//
+// An inode describes a single unnamed file.
+// The inode disk structure holds metadata: the file's type,
+// its size, the number of links referring to it, and the
+// list of blocks holding the file's content.
+//
+// The inodes are laid out sequentially on disk at
+// sb.startinode. Each inode has a number, indicating its
+// position on the disk.
+//
+// The kernel keeps a cache of in-use inodes in memory
+// to provide a place for synchronizing access
+// to inodes used by multiple processes. The cached
+// inodes include book-keeping information that is
+// not stored on disk: refcnt and valid.
+//
+// An inode and its in-memory representation go through a
+// sequence of states before they can be used by the
+// rest of the file system code.
+//
+// * Allocation: an inode is allocated if its type (on disk)
+//   is non-zero. ialloc() allocates, and dropping the last
+//   reference frees if the reference and link counts have
+//   fallen to zero.
+//
+// * Referencing in cache: an entry in the inode cache
+//   is free if refcnt is zero. Otherwise refcnt tracks
+//   the number of in-memory references to the entry
+//   (open files and current directories). iget() finds or
+//   creates a cache entry and increments its refcnt; cloning
+//   an Inode increments it and dropping one decrements it.
+//
+// * Valid: the information (type, size, &c) in an inode
+//   cache entry is only correct when valid is true.
+//   ilock() reads the inode from
+//   the disk and sets valid, while the last drop clears
+//   valid if refcnt has fallen to zero.
+//
+// * Locked: file system code may only examine and modify
+//   the information in an inode and its content if it
+//   has first locked the inode.
+//
+// Thus a typical sequence is:
+//   let ip = iget(dev, inum);
+//   let mut g = ip.ilock();
+//   ... examine and modify g.xxx ...
+//   drop(g);
+//   drop(ip);
+//
+// ilock() is separate from iget() so that system calls can
+// get a long-term reference to an inode (as for an open file)
+// and only lock it for short periods (e.g., in read()).
+// The separation also helps avoid deadlock and races during
+// pathname lookup. iget() increments refcnt so that the inode
+// stays cached and pointers to it remain valid.
+//
+// Many internal file system functions expect the caller to
+// have locked the inodes involved; the InodeGuard they take
+// is the proof of that.
+//
+// The icache spinlock protects the allocation of icache
+// entries. Since refcnt indicates whether an entry is free,
+// and dev and inum indicate which i-node an entry
+// holds, one must hold the icache lock while using any of those
+// fields. valid is also kept there, but only changes while the
+// entry's sleep-lock is held (or nobody references the entry).
+//
+// An inode's sleep-lock protects all fields of its on-disk
+// content.
+
+#[derive(Clone, Copy)]
+struct InodeMeta {
+    dev: u32,    // Device number
+    inum: u32,   // Inode number
+    refcnt: i32, // Reference count
+    valid: bool, // inode has been read from disk?
+    mtime: u64,  // last modification, seconds since the epoch (0: unknown)
+}
+
+impl InodeMeta {
+    const fn new() -> Self {
+        InodeMeta {
+            dev: 0,
+            inum: 0,
+            refcnt: 0,
+            valid: false,
+            mtime: 0,
+        }
+    }
+}
+
+static icache: SpinLock<[InodeMeta; NINODE]> = SpinLock::new("icache", {
+    const INIT: InodeMeta = InodeMeta::new();
+    [INIT; NINODE]
+});
+
+// copy of disk inode, one per icache entry
+static inodes: [SleepLock<InodeContent>; NINODE] = {
+    const INIT: SleepLock<InodeContent> = SleepLock::new("inode", InodeContent::new());
+    [INIT; NINODE]
+};
+
+// in-memory inode: a counted reference to an icache entry.
+// Cloning takes another reference; dropping one releases it
+// (and frees the inode on disk once there are no links to it).
+pub struct Inode {
+    pub dev: u32,
+    pub inum: u32,
+    idx: usize,
+}
+
+// A locked inode.  Derefs to the inode's content.
+pub struct InodeGuard<'a> {
+    ip: &'a Inode,
+    content: SleepLockGuard<'static, InodeContent>,
+}
+
+pub fn iinit(dev: u32) {
+    let s = readsb(dev);
+    *sblock.lock() = s;
+    println!(
+        "sb: size {} nblocks {} ninodes {} nlog {} logstart {} inodestart {} bmap start {}",
+        s.size, s.nblocks, s.ninodes, s.nlog, s.logstart, s.inodestart, s.bmapstart
+    );
+}
+
+// Allocate an inode on device dev.
+// Mark it as allocated by giving it type file_type.
+// Returns an unlocked but allocated and referenced inode.
+pub fn ialloc(dev: u32, file_type: i16) -> Inode {
+    let sb = getsb();
+    for inum in 1..sb.ninodes {
+        let mut bp = bio::bread(dev, iblock(inum, &sb));
+        let dip = &mut block_as::<InodeContent>(&mut bp)[(inum % IPB) as usize];
+        if dip.file_type == 0 {
+            // a free inode
+            *dip = InodeContent::new();
+            dip.file_type = file_type;
+            log::log_write(&mut bp); // mark it allocated on the disk
+            drop(bp);
+            return iget(dev, inum);
+        }
+    }
+    panic!("ialloc: no inodes");
+}
+
+// Find the inode with number inum on device dev
+// and return the in-memory copy. Does not lock
+// the inode and does not read it from disk.
+pub fn iget(dev: u32, inum: u32) -> Inode {
+    let mut ic = icache.lock();
+
+    // Is the inode already cached?
+    let mut empty = None;
+    for (i, m) in ic.iter_mut().enumerate() {
+        if m.refcnt > 0 && m.dev == dev && m.inum == inum {
+            m.refcnt += 1;
+            return Inode { dev, inum, idx: i };
+        }
+        if empty.is_none() && m.refcnt == 0 {
+            // Remember empty slot.
+            empty = Some(i);
+        }
+    }
+
+    // Recycle an inode cache entry.
+    let i = empty.expect("iget: no inodes");
+    ic[i] = InodeMeta {
+        dev,
+        inum,
+        refcnt: 1,
+        valid: false,
+        mtime: 0,
+    };
+    Inode { dev, inum, idx: i }
+}
+
+impl Inode {
+    // Lock the inode.
+    // Reads the inode from disk if necessary.
+    pub fn ilock(&self) -> InodeGuard {
+        let mut content = inodes[self.idx].lock();
+
+        if !icache.lock()[self.idx].valid {
+            let sb = getsb();
+            let mut bp = bio::bread(self.dev, iblock(self.inum, &sb));
+            let dip = &block_as::<InodeContent>(&mut bp)[(self.inum % IPB) as usize];
+            *content = *dip;
+            drop(bp);
+            icache.lock()[self.idx].valid = true;
+            if content.file_type == 0 {
+                panic!("ilock: no type");
+            }
+        }
+
+        InodeGuard { ip: self, content }
+    }
+}
+
+// Increment reference count for ip.
+impl Clone for Inode {
+    fn clone(&self) -> Self {
+        icache.lock()[self.idx].refcnt += 1;
+        Inode {
+            dev: self.dev,
+            inum: self.inum,
+            idx: self.idx,
+        }
+    }
+}
+
+// Drop a reference to an in-memory inode.
+// If that was the last reference, the inode cache entry can
+// be recycled.
+// If that was the last reference and the inode has no links
+// to it, free the inode (and its content) on disk.
+// All drops of an Inode must be inside a transaction in
+// case it has to free the inode.
+impl Drop for Inode {
+    fn drop(&mut self) {
+        let content = inodes[self.idx].lock();
+        let (valid, refcnt) = {
+            let ic = icache.lock();
+            (ic[self.idx].valid, ic[self.idx].refcnt)
+        };
+        if valid && content.nlink == 0 && refcnt == 1 {
+            // inode has no links and no other references: truncate and free.
+            let mut ip = InodeGuard { ip: self, content };
+            ip.itrunc();
+            ip.file_type = 0;
+            ip.iupdate();
+            icache.lock()[self.idx].valid = false;
+        } else {
+            drop(content);
+        }
+
+        icache.lock()[self.idx].refcnt -= 1;
+    }
+}
+
+impl<'a> InodeGuard<'a> {
+    pub fn inode(&self) -> &'a Inode {
+        self.ip
+    }
+
+    // Copy a modified in-memory inode to disk.
+    // Must be called after every change to a field
+    // that lives on disk.
+    pub fn iupdate(&self) {
+        let sb = getsb();
+        let mut bp = bio::bread(self.ip.dev, iblock(self.ip.inum, &sb));
+        block_as::<InodeContent>(&mut bp)[(self.ip.inum % IPB) as usize] = *self.content;
+        log::log_write(&mut bp);
+    }
+
+    // Inode content
+    //
+    // The content (data) associated with each inode is stored
+    // in blocks on the disk. The first NDIRECT block numbers
+    // are listed in addrs[].  The next NINDIRECT blocks are
+    // listed in block addrs[NDIRECT].
+
+    // Return the disk block address of the nth block in inode ip.
+    // If there is no such block, bmap allocates one.
+    fn bmap(&mut self, bn: usize) -> u32 {
+        let dev = self.ip.dev;
+
+        if bn < NDIRECT {
+            if self.addrs[bn] == 0 {
+                self.addrs[bn] = balloc(dev) as usize;
+            }
+            return self.addrs[bn] as u32;
+        }
+        let bn = bn - NDIRECT;
+
+        if bn < NINDIRECT {
+            // Load indirect block, allocating if necessary.
+            if self.addrs[NDIRECT] == 0 {
+                self.addrs[NDIRECT] = balloc(dev) as usize;
+            }
+            let mut bp = bio::bread(dev, self.addrs[NDIRECT] as u32);
+            let a = block_as::<u32>(&mut bp);
+            if a[bn] == 0 {
+                a[bn] = balloc(dev);
+                let addr = a[bn];
+                log::log_write(&mut bp);
+                return addr;
+            }
+            return a[bn];
+        }
+
+        panic!("bmap: out of range");
+    }
+
+    // Truncate inode (discard contents).
+    // Only called when the inode has no links
+    // to it (no directory entries referring to it)
+    // and has no in-memory reference to it (is
+    // not an open file or current directory).
+    pub fn itrunc(&mut self) {
+        let dev = self.ip.dev;
+
+        for i in 0..NDIRECT {
+            if self.addrs[i] != 0 {
+                bfree(dev, self.addrs[i] as u32);
+                self.addrs[i] = 0;
+            }
+        }
+
+        if self.addrs[NDIRECT] != 0 {
+            let mut bp = bio::bread(dev, self.addrs[NDIRECT] as u32);
+            for &a in block_as::<u32>(&mut bp).iter() {
+                if a != 0 {
+                    bfree(dev, a);
+                }
+            }
+            drop(bp);
+            bfree(dev, self.addrs[NDIRECT] as u32);
+            self.addrs[NDIRECT] = 0;
+        }
+
+        self.size = 0;
+        mmap::shtrunc(dev, self.ip.inum);
+        self.touch();
+        self.iupdate();
+    }
+
+    // Stamp the inode as modified now.
+    // The xv6 dinode has no room for a timestamp, so the
+    // modification time only lives in the inode cache.
+    fn touch(&self) {
+        icache.lock()[self.ip.idx].mtime = rtc::now();
+    }
+
+    // Last modification time, in seconds since the epoch,
+    // or 0 if it hasn't been modified since it was cached.
+    pub fn mtime(&self) -> u64 {
+        icache.lock()[self.ip.idx].mtime
+    }
+
+    // Copy stat information from inode.
+    pub fn stati(&self) -> stat::stat {
+        stat::stat {
+            file_type: self.file_type,
+            dev: self.ip.dev as i32,
+            ino: self.ip.inum,
+            nlink: self.nlink,
+            size: self.size as u32,
+        }
+    }
+
+    // Read data from inode.
+    // Device inodes are read through devsw by the file layer.
+    pub fn readi(&mut self, dst: &mut [u8], off: usize) -> Result<usize, &'static str> {
+        let size = self.size;
+        if off > size || off.checked_add(dst.len()).is_none() {
+            return Err("readi: bad offset");
+        }
+        let n = core::cmp::min(dst.len(), size - off);
+
+        let mut tot = 0;
+        while tot < n {
+            let off = off + tot;
+            let bp = bio::bread(self.ip.dev, self.bmap(off / BSIZE));
+            let m = core::cmp::min(n - tot, BSIZE - off % BSIZE);
+            dst[tot..tot + m].copy_from_slice(&bp.data[off % BSIZE..off % BSIZE + m]);
+            tot += m;
+        }
+        Ok(n)
+    }
+
+    // Write data to inode.
+    // Device inodes are written through devsw by the file layer.
+    pub fn writei(&mut self, src: &[u8], off: usize) -> Result<usize, &'static str> {
+        let n = src.len();
+        if off > self.size || off.checked_add(n).is_none() {
+            return Err("writei: bad offset");
+        }
+        if off + n > MAXFILE * BSIZE {
+            return Err("writei: file too large");
+        }
+
+        let mut tot = 0;
+        while tot < n {
+            let off = off + tot;
+            let mut bp = bio::bread(self.ip.dev, self.bmap(off / BSIZE));
+            let m = core::cmp::min(n - tot, BSIZE - off % BSIZE);
+            bp.data[off % BSIZE..off % BSIZE + m].copy_from_slice(&src[tot..tot + m]);
+            log::log_write(&mut bp);
+            tot += m;
+        }
+
+        if n > 0 && off + n > self.size {
+            self.size = off + n;
+        }
+        if n > 0 {
+            mmap::shwrite(self.ip.dev, self.ip.inum, src, off);
+            self.touch();
+        }
+        // write the inode back to disk even if the size didn't change
+        // because the loop above might have called bmap() and added a new
+        // block to self.addrs[].
+        self.iupdate();
+        Ok(n)
+    }
+
+    // Directories
+
+    // Read the directory entry at offset off.
+    pub fn readdirent(&mut self, off: usize) -> dirent {
+        let mut de = dirent::default();
+        match self.readi(mut_bytes_from_ref(&mut de), off) {
+            Ok(n) if n == DIRENT_SIZE => de,
+            _ => panic!("readdirent"),
+        }
+    }
+
+    // Look for a directory entry in a directory.
+    // If found, return the entry's inode and its byte offset.
+    pub fn dirlookup(&mut self, name: &[u8]) -> Option<(Inode, usize)> {
+        if self.file_type != T_DIR {
+            panic!("dirlookup not DIR");
+        }
+
+        let mut off = 0;
+        while off < self.size {
+            let de = self.readdirent(off);
+            if de.inum != 0 && namecmp(name, &de.name) {
+                // entry matches path element
+                return Some((iget(self.ip.dev, de.inum as u32), off));
+            }
+            off += DIRENT_SIZE;
+        }
+
+        None
+    }
+
+    // Write a new directory entry (name, inum) into the directory.
+    pub fn dirlink(&mut self, name: &[u8], inum: u32) -> Result<(), &'static str> {
+        // Check that name is not present.
+        if self.dirlookup(name).is_some() {
+            return Err("dirlink: name exists");
+        }
+
+        // Look for an empty dirent.
+        let mut off = 0;
+        while off < self.size {
+            if self.readdirent(off).inum == 0 {
+                break;
+            }
+            off += DIRENT_SIZE;
+        }
+
+        let mut de = dirent {
+            inum: inum as u16,
+            name: [0; DIRSIZ],
+        };
+        let len = core::cmp::min(name.len(), DIRSIZ);
+        de.name[..len].copy_from_slice(&name[..len]);
+        match self.writei(bytes_from_ref(&de), off) {
+            Ok(n) if n == DIRENT_SIZE => Ok(()),
+            _ => panic!("dirlink"),
+        }
+    }
+}
+
+impl<'a> Deref for InodeGuard<'a> {
+    type Target = InodeContent;
+    fn deref(&self) -> &InodeContent {
+        &self.content
+    }
+}
+impl<'a> DerefMut for InodeGuard<'a> {
+    fn deref_mut(&mut self) -> &mut InodeContent {
+        &mut self.content
+    }
+}
+
+// Compare a path element with a directory entry name,
+// which is NUL-padded to DIRSIZ bytes.
+pub fn namecmp(s: &[u8], t: &[u8; DIRSIZ]) -> bool {
+    let s = &s[..core::cmp::min(s.len(), DIRSIZ)];
+    let tlen = t.iter().position(|&c| c == 0).unwrap_or(DIRSIZ);
+    s == &t[..tlen]
+}
+
+// Paths
+
+// Copy the next path element from path into name.
+// Return the rest of path, with no leading slashes,
+// or None if there is no element to remove.
+// If the element is DIRSIZ bytes or longer, it is truncated.
+//
+// Examples:
+//   skipelem("a/bb/c", name) = Some("bb/c"), setting name = "a"
+//   skipelem("///a//bb", name) = Some("bb"), setting name = "a"
+//   skipelem("a", name) = Some(""), setting name = "a"
+//   skipelem("", name) = skipelem("////", name) = None
+fn skipelem<'p>(path: &'p [u8], name: &mut [u8; DIRSIZ]) -> Option<&'p [u8]> {
+    let start = path.iter().position(|&c| c != b'/')?;
+    let path = &path[start..];
+    let len = path.iter().position(|&c| c == b'/').unwrap_or(path.len());
+
+    fill(name, 0);
+    let n = core::cmp::min(len, DIRSIZ);
+    name[..n].copy_from_slice(&path[..n]);
+
+    let rest = &path[len..];
+    let skip = rest.iter().position(|&c| c != b'/').unwrap_or(rest.len());
+    Some(&rest[skip..])
+}
+
+// Look up and return the inode for a path name.
+// If parent is true, return the inode for the parent and copy the final
+// path element into name, which must have room for DIRSIZ bytes.
+// Must be called inside a transaction since it drops inodes.
+fn namex(path: &[u8], parent: bool, name: &mut [u8; DIRSIZ]) -> Option<Inode> {
+    let mut ip = if path.first() == Some(&b'/') {
+        iget(ROOTDEV, ROOTINO)
+    } else {
+        proc::myproc()
+            .and_then(|p| p.cwd.clone())
+            .expect("namex: no cwd")
+    };
+
+    let mut path = path;
+    while let Some(rest) = skipelem(path, name) {
+        let next = {
+            let mut g = ip.ilock();
+            if g.file_type != T_DIR {
+                return None;
+            }
+            if parent && rest.is_empty() {
+                // Stop one level early.
+                drop(g);
+                return Some(ip);
+            }
+            g.dirlookup(&name[..])?.0
+        };
+        ip = next;
+        path = rest;
+    }
+    if parent {
+        return None;
+    }
+    Some(ip)
+}
+
+pub fn namei(path: &[u8]) -> Option<Inode> {
+    let mut name = [0; DIRSIZ];
+    namex(path, false, &mut name)
+}
+
+pub fn nameiparent(path: &[u8], name: &mut [u8; DIRSIZ]) -> Option<Inode> {
+    namex(path, true, name)
+}
@@ -0,0 +1,193 @@
+//
+// Kernel heap: a size-class allocator on top of kalloc(),
+// registered as the global allocator so that Box, Vec, BTreeMap
+// and friends from the alloc crate can be used in the kernel.
+// Each class hands out power-of-two sized objects carved out of
+// whole pages; freed objects go back on their class's free list,
+// and the pages are never returned to kalloc().
+// The largest class is a whole page taken straight from kalloc(),
+// so nothing bigger than PGSIZE can be allocated.
+//
+
+use core::alloc::{GlobalAlloc, Layout};
+
+use super::kalloc;
+use super::mmu::{Page, PGSIZE};
+use super::spinlock::SpinLock;
+use super::utils::pointer::Ptr;
+
+// Object sizes of the size classes.
+const CLASSES: [usize; NCLASS] = [16, 32, 64, 128, 256, 512, 1024, 2048, PGSIZE];
+const NCLASS: usize = 9;
+
+#[repr(C)]
+struct Run {
+    next: Ptr<Run>,
+}
+
+// Usage of one size class.
+#[derive(Debug, Copy, Clone)]
+pub struct ClassStat {
+    pub size: usize,   // Object size
+    pub pages: usize,  // Pages taken from kalloc()
+    pub inuse: usize,  // Objects currently allocated
+    pub nalloc: usize, // Allocations so far
+}
+
+impl ClassStat {
+    const fn new() -> Self {
+        ClassStat {
+            size: 0,
+            pages: 0,
+            inuse: 0,
+            nalloc: 0,
+        }
+    }
+}
+
+struct Heap {
+    use_lock: bool,
+    freelist: [Ptr<Run>; NCLASS], // Free objects of each class
+    stats: [ClassStat; NCLASS],
+}
+
+static kheap: SpinLock<Heap> = SpinLock::new(
+    "kheap",
+    Heap {
+        use_lock: false,
+        freelist: [Ptr::null(); NCLASS],
+        stats: [ClassStat::new(); NCLASS],
+    },
+);
+
+pub struct HeapAllocator;
+
+#[global_allocator]
+static allocator: HeapAllocator = HeapAllocator;
+
+//------------------------------------------------------------------------------
+
+// The heap is usable from the start, since mp_init() already
+// allocates, but it can only be locked once mycpu() works on
+// every cpu.  main() calls heapinit() after kinit2().
+pub fn heapinit() {
+    unsafe {
+        kheap_unlocked().use_lock = true;
+    }
+}
+
+// Access kheap without taking the lock.
+// Only valid while use_lock is false (see kmem in kalloc.rs).
+unsafe fn kheap_unlocked() -> &'static mut Heap {
+    &mut *kheap.as_mut_ptr()
+}
+
+// Run f with kheap, holding kheap.lock if required.
+fn with_heap<R>(f: impl FnOnce(&mut Heap) -> R) -> R {
+    let h = unsafe { kheap_unlocked() };
+    if h.use_lock {
+        let mut h = kheap.lock();
+        f(&mut h)
+    } else {
+        f(h)
+    }
+}
+
+// Index of the smallest size class that fits layout.
+// Objects are aligned to their size, so that also covers the alignment.
+fn class(layout: Layout) -> Option<usize> {
+    let size = core::cmp::max(layout.size(), layout.align());
+    CLASSES.iter().position(|s| *s >= size)
+}
+
+impl Heap {
+    // Carve a fresh page into free objects of class c.
+    fn grow(&mut self, c: usize) -> Option<()> {
+        let page = kalloc::kalloc()?;
+        let mut r = Ptr::<Run>::from(page.as_ptr() as *const Run);
+        for _ in 0..PGSIZE / CLASSES[c] {
+            (*r).next = self.freelist[c];
+            self.freelist[c] = r;
+            r.increase_bytes(CLASSES[c]).unwrap();
+        }
+        self.stats[c].pages += 1;
+        Some(())
+    }
+
+    fn alloc(&mut self, c: usize) -> Option<*mut u8> {
+        let p = if CLASSES[c] == PGSIZE {
+            let page = kalloc::kalloc()?;
+            self.stats[c].pages += 1;
+            page.as_mut_ptr()
+        } else {
+            if self.freelist[c].is_null() {
+                self.grow(c)?;
+            }
+            let r = self.freelist[c];
+            self.freelist[c] = (*r).next;
+            r.get_mut() as *mut u8
+        };
+        self.stats[c].inuse += 1;
+        self.stats[c].nalloc += 1;
+        Some(p)
+    }
+
+    fn free(&mut self, p: *mut u8, c: usize) {
+        if self.stats[c].inuse == 0 {
+            panic!("kheap: free");
+        }
+        self.stats[c].inuse -= 1;
+        if CLASSES[c] == PGSIZE {
+            self.stats[c].pages -= 1;
+            kalloc::kfree(unsafe { &mut *(p as *mut Page) });
+            return;
+        }
+        let mut r = Ptr::from(p as *const Run);
+        (*r).next = self.freelist[c];
+        self.freelist[c] = r;
+    }
+}
+
+unsafe impl GlobalAlloc for HeapAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        class(layout)
+            .and_then(|c| with_heap(|h| h.alloc(c)))
+            .unwrap_or(core::ptr::null_mut())
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let c = class(layout).expect("kheap: dealloc");
+        with_heap(|h| h.free(ptr, c));
+    }
+}
+
+#[alloc_error_handler]
+fn alloc_error(layout: Layout) -> ! {
+    panic!(
+        "out of kernel heap: size {} align {}",
+        layout.size(),
+        layout.align()
+    );
+}
+
+// Usage of each size class.
+pub fn heapstats() -> [ClassStat; NCLASS] {
+    with_heap(|h| {
+        let mut stats = h.stats;
+        for (s, size) in stats.iter_mut().zip(CLASSES.iter()) {
+            s.size = *size;
+        }
+        stats
+    })
+}
+
+// Print heap usage to the console.
+// Runs when user types ^P on console, along with procdump().
+pub fn heapdump() {
+    for s in heapstats().iter() {
+        println!(
+            "heap {}: {} in use, {} allocs, {} pages",
+            s.size, s.inuse, s.nalloc, s.pages
+        );
+    }
+}
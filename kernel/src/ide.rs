@@ -0,0 +1,196 @@
+// Simple PIO-based (non-DMA) IDE driver code.
+
+use super::bio::BlockDevice;
+use super::buf::{Buf, BufFlags};
+use super::fs::BSIZE;
+use super::ioapic;
+use super::mp;
+use super::param;
+use super::proc;
+use super::spinlock::SpinLock;
+use super::traps;
+use super::utils::address::vaddr;
+use super::x86;
+
+const SECTOR_SIZE: usize = 512;
+const IDE_BSY: u8 = 0x80;
+const IDE_DRDY: u8 = 0x40;
+const IDE_DF: u8 = 0x20;
+const IDE_ERR: u8 = 0x01;
+
+const IDE_CMD_READ: u8 = 0x20;
+const IDE_CMD_WRITE: u8 = 0x30;
+const IDE_CMD_RDMUL: u8 = 0xc4;
+const IDE_CMD_WRMUL: u8 = 0xc5;
+
+// idequeue points to the buf now being read/written to the disk.
+// idequeue->qnext points to the next buf to be processed.
+// You must hold idelock while manipulating queue.
+struct IdeQueue {
+    head: *mut Buf,
+    havedisk1: bool,
+}
+
+// The queued bufs are only touched with idelock held.
+unsafe impl Send for IdeQueue {}
+
+static idelock: SpinLock<IdeQueue> = SpinLock::new(
+    "ide",
+    IdeQueue {
+        head: core::ptr::null_mut(),
+        havedisk1: false,
+    },
+);
+
+// Wait for IDE disk to become ready.
+fn idewait(checkerr: bool) -> Result<(), &'static str> {
+    let r = loop {
+        let r = x86::inb(0x1f7);
+        if r & (IDE_BSY | IDE_DRDY) == IDE_DRDY {
+            break r;
+        }
+    };
+    if checkerr && r & (IDE_DF | IDE_ERR) != 0 {
+        return Err("idewait: device error");
+    }
+    Ok(())
+}
+
+pub fn ideinit() {
+    let ncpu = unsafe { mp::CPU_ARRAY.len() };
+    ioapic::ioapic_enable(traps::IRQ_IDE, (ncpu - 1) as u32);
+    idewait(false).unwrap();
+
+    // Check if disk 1 is present
+    x86::outb(0x1f6, 0xe0 | (1 << 4));
+    for _ in 0..1000 {
+        if x86::inb(0x1f7) != 0 {
+            idelock.lock().havedisk1 = true;
+            break;
+        }
+    }
+
+    // Switch back to disk 0.
+    x86::outb(0x1f6, 0xe0 | (0 << 4));
+}
+
+// Start the request for b.  Caller must hold idelock.
+fn idestart(b: &Buf) {
+    if b.blockno as usize >= param::FSSIZE {
+        panic!("incorrect blockno");
+    }
+    let sector_per_block = BSIZE / SECTOR_SIZE;
+    let sector = b.blockno as usize * sector_per_block;
+    let read_cmd = if sector_per_block == 1 {
+        IDE_CMD_READ
+    } else {
+        IDE_CMD_RDMUL
+    };
+    let write_cmd = if sector_per_block == 1 {
+        IDE_CMD_WRITE
+    } else {
+        IDE_CMD_WRMUL
+    };
+
+    if sector_per_block > 7 {
+        panic!("idestart");
+    }
+
+    idewait(false).unwrap();
+    x86::outb(0x3f6, 0); // generate interrupt
+    x86::outb(0x1f2, sector_per_block as u8); // number of sectors
+    x86::outb(0x1f3, trunc8!(sector));
+    x86::outb(0x1f4, trunc8!(sector >> 8));
+    x86::outb(0x1f5, trunc8!(sector >> 16));
+    x86::outb(
+        0x1f6,
+        0xe0 | (trunc8!(b.dev & 1) << 4) | trunc8!((sector >> 24) & 0x0f),
+    );
+    if b.flags.contains(BufFlags::DIRTY) {
+        x86::outb(0x1f7, write_cmd);
+        x86::outsl(0x1f0, b.data.as_ptr() as *const u32, BSIZE / 4);
+    } else {
+        x86::outb(0x1f7, read_cmd);
+    }
+}
+
+// Interrupt handler.
+pub fn ideintr() {
+    // First queued buffer is the active request.
+    let mut q = idelock.lock();
+
+    if q.head.is_null() {
+        // println!("spurious IDE interrupt");
+        return;
+    }
+    let b = unsafe { &mut *q.head };
+    q.head = b.qnext;
+
+    // Read data if needed.
+    if !b.flags.contains(BufFlags::DIRTY) && idewait(true).is_ok() {
+        x86::insl(0x1f0, b.data.as_mut_ptr() as *mut u32, BSIZE / 4);
+    }
+
+    // Wake process waiting for this buf.
+    b.flags.insert(BufFlags::VALID);
+    b.flags.remove(BufFlags::DIRTY);
+    proc::wakeup(vaddr::from_ptr(b as *const Buf).unwrap());
+
+    // Start disk on next buf in queue.
+    if !q.head.is_null() {
+        idestart(unsafe { &*q.head });
+    }
+}
+
+// Sync buf with disk.
+// If DIRTY is set, write buf to disk, clear DIRTY, set VALID.
+// Else if VALID is not set, read buf from disk, set VALID.
+// The caller must hold the buffer's sleep-lock.
+pub fn iderw(b: &mut Buf) {
+    if b.flags & (BufFlags::VALID | BufFlags::DIRTY) == BufFlags::VALID {
+        panic!("iderw: nothing to do");
+    }
+
+    let mut q = idelock.lock();
+
+    if b.dev != 0 && !q.havedisk1 {
+        panic!("iderw: ide disk 1 not present");
+    }
+
+    // Append b to idequeue.
+    // From here on the interrupt handler may touch the buf,
+    // so it is only accessed through the raw pointer.
+    b.qnext = core::ptr::null_mut();
+    let b = b as *mut Buf;
+    unsafe {
+        let mut pp: *mut *mut Buf = &mut q.head;
+        while !(*pp).is_null() {
+            pp = &mut (**pp).qnext;
+        }
+        *pp = b;
+    }
+
+    // Start disk if necessary.
+    if q.head == b {
+        idestart(unsafe { &*b });
+    }
+
+    // Wait for request to finish.
+    let chan = vaddr::from_ptr(b).unwrap();
+    while unsafe { core::ptr::read_volatile(&(*b).flags) } & (BufFlags::VALID | BufFlags::DIRTY)
+        != BufFlags::VALID
+    {
+        q = proc::sleep(chan, q);
+    }
+}
+
+// The IDE disk as a block device for the buffer cache.
+pub struct IdeDisk;
+
+pub static IDEDISK: IdeDisk = IdeDisk;
+
+impl BlockDevice for IdeDisk {
+    fn rw(&self, b: &mut Buf) {
+        iderw(b);
+    }
+}
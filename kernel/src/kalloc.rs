@@ -1,10 +1,10 @@
-use super::spin::Mutex;
-
 use super::mmu;
 use super::mmu::Page;
+use super::spinlock::SpinLock;
 use super::utils;
 use super::utils::address::{p2v, paddr, paddr_pg, v2p, vaddr, vaddr_pg};
 use super::utils::pointer::Ptr;
+use super::vm::PHYSTOP;
 //------------------------------------------------------------------------------
 
 extern "C" {
@@ -17,11 +17,25 @@ struct Run {
     next: Ptr<Run>,
 }
 
-lazy_static! {
-    static ref freelist: Mutex<Ptr<Run>> = Mutex::new(Ptr::null());
+// Number of physical pages below PHYSTOP.
+const NPAGES: usize = PHYSTOP / mmu::PGSIZE;
+
+struct Kmem {
+    use_lock: bool,
+    freelist: Ptr<Run>,
+    nfree: usize,          // Number of pages on the free list
+    refcnt: [u16; NPAGES], // References to each physical page
 }
 
-const PHYSTOP: usize = 0xE000000;
+static kmem: SpinLock<Kmem> = SpinLock::new(
+    "kmem",
+    Kmem {
+        use_lock: false,
+        freelist: Ptr::null(),
+        nfree: 0,
+        refcnt: [0; NPAGES],
+    },
+);
 
 //------------------------------------------------------------------------------
 
@@ -64,11 +78,47 @@ pub fn kinit1(start: vaddr, end: vaddr) {
     }
 }
 
+pub fn kinit2(start: vaddr, end: vaddr) {
+    freerange(mmu::page_roundup(start), end.check_aligned().unwrap());
+    unsafe {
+        kmem_unlocked().use_lock = true;
+    }
+}
+
+// Access kmem without taking the lock.
+// Only valid while use_lock is false (i.e. before kinit2()),
+// when just this cpu runs and the lock cannot be used yet.
+unsafe fn kmem_unlocked() -> &'static mut Kmem {
+    &mut *kmem.as_mut_ptr()
+}
+
+// Run f with kmem, holding kmem.lock if required.
+fn with_kmem<R>(f: impl FnOnce(&mut Kmem) -> R) -> R {
+    let k = unsafe { kmem_unlocked() };
+    if k.use_lock {
+        let mut k = kmem.lock();
+        f(&mut k)
+    } else {
+        f(k)
+    }
+}
+
+// Index of the physical page at pa in kmem.refcnt.
+fn pgindex(pa: paddr) -> usize {
+    if pa >= PHYSTOP {
+        panic!("pgindex");
+    }
+    pa.as_raw() / mmu::PGSIZE
+}
+
 fn freerange(start: vaddr_pg, end: vaddr_pg) {
     println!("freerange: start={}, end={}", start, end);
     let mut p = Ptr::<Page>::from(start);
     let mut num_pages = 0;
     while p.address().next(mmu::PGSIZE) <= end {
+        // kfree drops the one reference a page on the free list lacks.
+        let idx = pgindex(v2p(p.address()));
+        with_kmem(|k| k.refcnt[idx] = 1);
         kfree(&mut *p);
         p.increase(1);
         num_pages += 1;
@@ -76,6 +126,9 @@ fn freerange(start: vaddr_pg, end: vaddr_pg) {
     println!("{} pages available", num_pages);
 }
 
+// Drop a reference to the page of physical memory pointed at by page,
+// which normally should have been returned by a call to kalloc().
+// The page goes back on the free list once the last reference is dropped.
 pub fn kfree<'a>(page: &'a mut Page) {
     if page.as_ptr() < unsafe { kernel_end.as_ptr() }
         || v2p(vaddr::from_ptr(page.as_ptr()).unwrap()) >= PHYSTOP
@@ -83,6 +136,18 @@ pub fn kfree<'a>(page: &'a mut Page) {
         panic!("kfree");
     }
 
+    let idx = pgindex(v2p(vaddr::from_ptr(page.as_ptr()).unwrap()));
+    let last = with_kmem(|k| {
+        if k.refcnt[idx] == 0 {
+            panic!("kfree: refcnt");
+        }
+        k.refcnt[idx] -= 1;
+        k.refcnt[idx] == 0
+    });
+    if !last {
+        return;
+    }
+
     let page: Ptr<Page> = Ptr::from(page.as_ptr() as *const Page);
 
     // Fill with junk to catch dangling refs.
@@ -91,23 +156,53 @@ pub fn kfree<'a>(page: &'a mut Page) {
     }
 
     let mut r = page.cast::<Run>();
-    {
-        let mut tmp = freelist.lock();
-        (*r).next = *tmp;
-        *tmp = r;
-    }
+    with_kmem(|k| {
+        (*r).next = k.freelist;
+        k.freelist = r;
+        k.nfree += 1;
+    });
 }
 
 // return Some(address) if there is an available page, otherwise None
+// The page starts with one reference.
 pub fn kalloc<'a>() -> Option<&'a mut Page> {
-    let r: Ptr<Run>;
-    {
-        let mut tmp = freelist.lock();
-        if tmp.is_null() {
-            return None;
+    let r = with_kmem(|k| {
+        let r = k.freelist;
+        if !r.is_null() {
+            k.freelist = (*r).next;
+            k.nfree -= 1;
+            k.refcnt[pgindex(v2p(r.address()))] = 1;
         }
-        r = *tmp;
-        *tmp = (*r).next;
+        r
+    });
+    if r.is_null() {
+        return None;
     }
     unsafe { r.cast::<Page>().get_mut().as_mut() }
 }
+
+// Take another reference to an allocated physical page.
+// Returns None if the page already has as many
+// references as its count can hold.
+pub fn incref(pa: paddr_pg) -> Option<()> {
+    let idx = pgindex(pa.into());
+    with_kmem(|k| {
+        if k.refcnt[idx] == 0 {
+            panic!("incref: free page");
+        }
+        k.refcnt[idx] = k.refcnt[idx].checked_add(1)?;
+        Some(())
+    })
+}
+
+// Number of references to the physical page at pa.
+pub fn refcount(pa: paddr_pg) -> usize {
+    let idx = pgindex(pa.into());
+    with_kmem(|k| k.refcnt[idx] as usize)
+}
+
+// Number of pages on the free list.
+#[cfg(test)]
+pub fn freecount() -> usize {
+    with_kmem(|k| k.nfree)
+}
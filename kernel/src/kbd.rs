@@ -0,0 +1,190 @@
+// PC keyboard interface constants
+
+use super::console;
+use super::x86;
+
+const KBSTATP: u16 = 0x64; // kbd controller status port(I)
+const KBS_DIB: u8 = 0x01; // kbd data in buffer
+const KBDATAP: u16 = 0x60; // kbd data port(I)
+
+const NO: u8 = 0;
+
+const SHIFT: u8 = 1 << 0;
+const CTL: u8 = 1 << 1;
+const ALT: u8 = 1 << 2;
+
+const CAPSLOCK: u8 = 1 << 3;
+const NUMLOCK: u8 = 1 << 4;
+const SCROLLLOCK: u8 = 1 << 5;
+
+const E0ESC: u8 = 1 << 6;
+
+// Special keycodes
+const KEY_HOME: u8 = 0xE0;
+const KEY_END: u8 = 0xE1;
+const KEY_UP: u8 = 0xE2;
+const KEY_DN: u8 = 0xE3;
+const KEY_LF: u8 = 0xE4;
+const KEY_RT: u8 = 0xE5;
+const KEY_PGUP: u8 = 0xE6;
+const KEY_PGDN: u8 = 0xE7;
+const KEY_INS: u8 = 0xE8;
+const KEY_DEL: u8 = 0xE9;
+
+// C('A') == Control-A
+fn C(x: u8) -> u8 {
+    x - b'@'
+}
+
+static shiftcode: [u8; 256] = assigned_array![
+    NO; 256;
+    [0x1D] = CTL,
+    [0x2A] = SHIFT,
+    [0x36] = SHIFT,
+    [0x38] = ALT,
+    [0x9D] = CTL,
+    [0xB8] = ALT
+];
+
+static togglecode: [u8; 256] = assigned_array![
+    NO; 256;
+    [0x3A] = CAPSLOCK,
+    [0x45] = NUMLOCK,
+    [0x46] = SCROLLLOCK
+];
+
+// E0-prefixed keys, shared by all maps.
+// The scancode of an E0-prefixed key has 0x80 or'ed in by kbdgetc.
+const E0KEYS: [(usize, u8); 10] = [
+    (0xC8, KEY_UP),
+    (0xD0, KEY_DN),
+    (0xC9, KEY_PGUP),
+    (0xD1, KEY_PGDN),
+    (0xCB, KEY_LF),
+    (0xCD, KEY_RT),
+    (0xC7, KEY_HOME),
+    (0xCF, KEY_END),
+    (0xD2, KEY_INS),
+    (0xD3, KEY_DEL),
+];
+
+// Build a 256-entry map from the scancodes starting at 0,
+// the keypad keys and the E0-prefixed keys.
+fn keymap(base: &[u8], kp_enter: u8, kp_div: u8) -> [u8; 256] {
+    let mut map = [NO; 256];
+    map[..base.len()].copy_from_slice(base);
+    map[0x9C] = kp_enter; // KP_Enter
+    map[0xB5] = kp_div; // KP_Div
+    for &(code, key) in E0KEYS.iter() {
+        map[code] = key;
+    }
+    map
+}
+
+lazy_static! {
+    static ref normalmap: [u8; 256] = keymap(
+        &[
+            NO, 0x1B, b'1', b'2', b'3', b'4', b'5', b'6', // 0x00
+            b'7', b'8', b'9', b'0', b'-', b'=', 0x08, b'\t', //
+            b'q', b'w', b'e', b'r', b't', b'y', b'u', b'i', // 0x10
+            b'o', b'p', b'[', b']', b'\n', NO, b'a', b's', //
+            b'd', b'f', b'g', b'h', b'j', b'k', b'l', b';', // 0x20
+            b'\'', b'`', NO, b'\\', b'z', b'x', b'c', b'v', //
+            b'b', b'n', b'm', b',', b'.', b'/', NO, b'*', // 0x30
+            NO, b' ', NO, NO, NO, NO, NO, NO, //
+            NO, NO, NO, NO, NO, NO, NO, b'7', // 0x40
+            b'8', b'9', b'-', b'4', b'5', b'6', b'+', b'1', //
+            b'2', b'3', b'0', b'.', NO, NO, NO, NO, // 0x50
+        ],
+        b'\n',
+        b'/',
+    );
+    static ref shiftmap: [u8; 256] = keymap(
+        &[
+            NO, 0x1B, b'!', b'@', b'#', b'$', b'%', b'^', // 0x00
+            b'&', b'*', b'(', b')', b'_', b'+', 0x08, b'\t', //
+            b'Q', b'W', b'E', b'R', b'T', b'Y', b'U', b'I', // 0x10
+            b'O', b'P', b'{', b'}', b'\n', NO, b'A', b'S', //
+            b'D', b'F', b'G', b'H', b'J', b'K', b'L', b':', // 0x20
+            b'"', b'~', NO, b'|', b'Z', b'X', b'C', b'V', //
+            b'B', b'N', b'M', b'<', b'>', b'?', NO, b'*', // 0x30
+            NO, b' ', NO, NO, NO, NO, NO, NO, //
+            NO, NO, NO, NO, NO, NO, NO, b'7', // 0x40
+            b'8', b'9', b'-', b'4', b'5', b'6', b'+', b'1', //
+            b'2', b'3', b'0', b'.', NO, NO, NO, NO, // 0x50
+        ],
+        b'\n',
+        b'/',
+    );
+    static ref ctlmap: [u8; 256] = keymap(
+        &[
+            NO, NO, NO, NO, NO, NO, NO, NO, //
+            NO, NO, NO, NO, NO, NO, NO, NO, //
+            C(b'Q'), C(b'W'), C(b'E'), C(b'R'), C(b'T'), C(b'Y'), C(b'U'), C(b'I'), //
+            C(b'O'), C(b'P'), NO, NO, b'\r', NO, C(b'A'), C(b'S'), //
+            C(b'D'), C(b'F'), C(b'G'), C(b'H'), C(b'J'), C(b'K'), C(b'L'), NO, //
+            NO, NO, NO, C(b'\\'), C(b'Z'), C(b'X'), C(b'C'), C(b'V'), //
+            C(b'B'), C(b'N'), C(b'M'), NO, NO, C(b'/'), NO, NO, //
+        ],
+        b'\r',
+        C(b'/'),
+    );
+}
+
+// Modifier and lock state.
+// Only touched by kbdgetc, which consoleintr calls with the console lock held.
+static mut shiftstate: u8 = 0;
+
+// Read a key from the keyboard controller.
+// Returns None if no data is waiting, and Some(0) for
+// scancodes that don't produce a character.
+pub fn kbdgetc() -> Option<u8> {
+    let st = x86::inb(KBSTATP);
+    if st & KBS_DIB == 0 {
+        return None;
+    }
+    let mut data = x86::inb(KBDATAP) as usize;
+    let shift = unsafe { &mut shiftstate };
+
+    if data == 0xE0 {
+        *shift |= E0ESC;
+        return Some(0);
+    } else if data & 0x80 != 0 {
+        // Key released
+        if *shift & E0ESC == 0 {
+            data &= 0x7F;
+        }
+        *shift &= !(shiftcode[data] | E0ESC);
+        return Some(0);
+    } else if *shift & E0ESC != 0 {
+        // Last character was an E0 escape; or with 0x80
+        data |= 0x80;
+        *shift &= !E0ESC;
+    } else if *shift & NUMLOCK == 0 && data >= 0x47 && data <= 0x53 {
+        // Without Num Lock the keypad doubles as the cursor keys.
+        if normalmap[data | 0x80] != NO {
+            data |= 0x80;
+        }
+    }
+
+    *shift |= shiftcode[data];
+    *shift ^= togglecode[data];
+    let map: &[u8; 256] = match *shift & (CTL | SHIFT) {
+        0 => &*normalmap,
+        SHIFT => &*shiftmap,
+        _ => &*ctlmap,
+    };
+    let mut c = map[data];
+    if *shift & CAPSLOCK != 0 {
+        if c.is_ascii_lowercase() {
+            c = c.to_ascii_uppercase();
+        } else if c.is_ascii_uppercase() {
+            c = c.to_ascii_lowercase();
+        }
+    }
+    Some(c)
+}
+
+pub fn kbdintr() {
+    console::consoleintr(kbdgetc);
+}
@@ -0,0 +1,79 @@
+// Kernel tests.
+//
+// `make test` builds the kernel with `cargo xtest` and boots it
+// in QEMU.  Instead of starting init, the first process runs the
+// #[test_case] functions from forkret() (see proc.rs), so tests may
+// sleep, fork and do I/O.  The buffer cache is backed by a RAM disk
+// (see ramdisk.rs).  Results go to the serial port, and QEMU exits
+// through its isa-debug-exit device with a status telling whether
+// all tests passed.
+
+use core::fmt::{self, Write};
+use core::panic::PanicInfo;
+
+use super::uart;
+use super::x86;
+
+const DEBUG_EXIT: u16 = 0xf4; // isa-debug-exit port, see the Makefile
+
+// QEMU exits with status (code << 1) | 1.
+#[derive(Clone, Copy)]
+#[repr(u16)]
+pub enum QemuExitCode {
+    Success = 0x10,
+    Failed = 0x11,
+}
+
+pub fn exit_qemu(code: QemuExitCode) -> ! {
+    x86::outw(DEBUG_EXIT, code as u16);
+    loop {}
+}
+
+struct Serial;
+
+impl fmt::Write for Serial {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.bytes() {
+            uart::putc_sync(c);
+        }
+        Ok(())
+    }
+}
+
+#[doc(hidden)]
+pub fn _print(args: fmt::Arguments) {
+    Serial.write_fmt(args).unwrap();
+}
+
+#[macro_export]
+macro_rules! test_println {
+    () => (crate::ktest::_print(format_args!("\n")));
+    ($($arg:tt)*) => (crate::ktest::_print(format_args!("{}\n", format_args!($($arg)*))));
+}
+
+pub trait Testable {
+    fn run(&self);
+}
+
+impl<T: Fn()> Testable for T {
+    fn run(&self) {
+        test_println!("{} ...", core::any::type_name::<T>());
+        self();
+        test_println!("ok");
+    }
+}
+
+pub fn runner(tests: &[&dyn Testable]) {
+    test_println!("running {} tests", tests.len());
+    for test in tests {
+        test.run();
+    }
+    test_println!("all tests passed");
+    exit_qemu(QemuExitCode::Success);
+}
+
+// Called by the panic handler: a test failed.
+pub fn fail(info: &PanicInfo) {
+    test_println!("FAILED: {}", info);
+    exit_qemu(QemuExitCode::Failed);
+}
@@ -1,4 +1,5 @@
 use super::traps;
+use super::utils::address::{p2v, paddr, paddr_raw};
 use super::x86;
 
 pub static mut lapic: *mut u32 = core::ptr::null_mut();
@@ -34,6 +35,23 @@ const TICR: usize = (0x0380 / 4); // Timer Initial Count
 const TCCR: usize = (0x0390 / 4); // Timer Current Count
 const TDCR: usize = (0x03E0 / 4); // Timer Divide Configuration
 
+// PIT channel 2, used to calibrate the LAPIC timer and the TSC.
+const PIT_HZ: u32 = 1193182; // PIT input clock
+const PIT_CH2: u16 = 0x42; // channel 2 data port
+const PIT_MODE: u16 = 0x43; // mode/command port
+const PIT_CH2_MODE0: u8 = 0xB0; // channel 2, lobyte/hibyte, mode 0
+const PORTB: u16 = 0x61; // system control port B
+const PORTB_GATE2: u8 = 0x01; // channel 2 gate
+const PORTB_SPKR: u8 = 0x02; // speaker data enable
+const PORTB_OUT2: u8 = 0x20; // channel 2 output (read only)
+const CALIBRATE_MS: u32 = 10;
+
+// Clock rates measured by calibrate().  Written once by the
+// boot CPU before the other CPUs start, read-only afterwards.
+static mut lapic_khz: u32 = 0; // LAPIC timer ticks per ms
+static mut tsc_khz: u64 = 0; // TSC ticks per ms
+static mut tsc_boot: u64 = 0; // TSC at calibration
+
 fn lapic_write(index: usize, value: u32) {
     unsafe {
         core::ptr::write_volatile(lapic.add(index), value);
@@ -44,7 +62,49 @@ fn lapic_read(index: usize) -> u32 {
     unsafe { core::ptr::read_volatile(lapic.add(index)) }
 }
 
-pub fn lapic_init() {
+// Measure the LAPIC timer and TSC rates by letting
+// PIT channel 2 count down CALIBRATE_MS milliseconds.
+fn calibrate() {
+    let count = PIT_HZ / 1000 * CALIBRATE_MS;
+
+    // Stop channel 2 and keep the speaker quiet while loading the count.
+    let portb = x86::inb(PORTB) & !(PORTB_GATE2 | PORTB_SPKR);
+    x86::outb(PORTB, portb);
+    x86::outb(PIT_MODE, PIT_CH2_MODE0);
+    x86::outb(PIT_CH2, trunc8!(count));
+    x86::outb(PIT_CH2, trunc8!(count >> 8));
+
+    // Let the LAPIC timer count down from the top, without interrupting.
+    lapic_write(TDCR, X1);
+    lapic_write(TIMER, MASKED);
+    lapic_write(TICR, 0xFFFFFFFF);
+    let tsc0 = x86::rdtsc();
+
+    // Raising the gate starts the countdown; OUT2 goes high at zero.
+    x86::outb(PORTB, portb | PORTB_GATE2);
+    while x86::inb(PORTB) & PORTB_OUT2 == 0 {
+        x86::nop();
+    }
+
+    let tsc1 = x86::rdtsc();
+    let elapsed = 0xFFFFFFFF - lapic_read(TCCR);
+    x86::outb(PORTB, portb);
+    lapic_write(TICR, 0);
+
+    unsafe {
+        lapic_khz = elapsed / CALIBRATE_MS;
+        tsc_khz = (tsc1 - tsc0) / CALIBRATE_MS as u64;
+        tsc_boot = tsc0;
+        println!("lapic: timer {} kHz, tsc {} kHz", lapic_khz, tsc_khz);
+    }
+}
+
+// Set up this CPU's local APIC, with the timer
+// interrupting hz times a second.
+pub fn lapic_init(hz: u32) {
+    if hz == 0 {
+        panic!("lapic_init: zero timer rate");
+    }
     if unsafe { lapic.is_null() } {
         return;
     }
@@ -52,13 +112,17 @@ pub fn lapic_init() {
     // Enable local APIC; set spurious interrupt vector.
     lapic_write(SVR, ENABLE | (traps::T_IRQ0 + traps::IRQ_SPURIOUS));
 
+    // The boot CPU measures the clocks; the others reuse its numbers.
+    if unsafe { lapic_khz == 0 } {
+        calibrate();
+    }
+
     // The timer repeatedly counts down at bus frequency
     // from lapic[TICR] and then issues an interrupt.
-    // If xv6 cared more about precise timekeeping,
-    // TICR would be calibrated using an external time source.
     lapic_write(TDCR, X1);
     lapic_write(TIMER, PERIODIC | (traps::T_IRQ0 + traps::IRQ_TIMER));
-    lapic_write(TICR, 10000000);
+    let count = unsafe { lapic_khz } as u64 * 1000 / hz as u64;
+    lapic_write(TICR, core::cmp::min(count, u32::max_value() as u64) as u32);
 
     // Disable logical interrupt lines.
     lapic_write(LINT0, MASKED);
@@ -89,9 +153,71 @@ pub fn lapic_init() {
     lapic_write(TPR, 0);
 }
 
+// Acknowledge interrupt.
+pub fn lapiceoi() {
+    if unsafe { !lapic.is_null() } {
+        lapic_write(EOI, 0);
+    }
+}
+
 // Spin for a given number of microseconds.
-// On read hardware would want to tune this dynamically.
-pub fn microdelay(us: u32) {}
+pub fn microdelay(us: u32) {
+    let end = x86::rdtsc() + us as u64 * unsafe { tsc_khz } / 1000;
+    while x86::rdtsc() < end {
+        x86::nop();
+    }
+}
+
+// Nanoseconds since the clocks were calibrated at boot.
+// Assumes the TSCs of all CPUs tick in step, as they do
+// under QEMU and on CPUs with an invariant TSC.  A CPU whose
+// TSC is still behind the calibration reading gets 0.
+pub fn nanotime() -> u64 {
+    let khz = unsafe { tsc_khz };
+    if khz == 0 {
+        return 0;
+    }
+    let elapsed = x86::rdtsc().saturating_sub(unsafe { tsc_boot });
+    let per_sec = khz * 1000;
+    elapsed / per_sec * 1_000_000_000 + elapsed % per_sec * 1_000_000 / khz
+}
+
+pub const CMOS_PORT: u16 = 0x70;
+pub const CMOS_RETURN: u16 = 0x71;
+
+// Start additional processor running entry code at addr.
+// See Appendix B of MultiProcessor Specification.
+pub fn lapic_startap(apicid: u8, addr: paddr) {
+    // "The BSP must initialize CMOS shutdown code to 0AH
+    // and the warm reset vector (DWORD based at 40:67) to point at
+    // the AP startup code prior to the [universal startup algorithm]."
+    x86::outb(CMOS_PORT, 0xF); // offset 0xF is shutdown code
+    x86::outb(CMOS_RETURN, 0x0A);
+    let wrv = p2v(paddr_raw((0x40 << 4) | 0x67)).as_mut_ptr::<u16>(); // Warm reset vector
+    unsafe {
+        core::ptr::write_volatile(wrv, 0);
+        core::ptr::write_volatile(wrv.add(1), (addr.as_raw() >> 4) as u16);
+    }
+
+    // "Universal startup algorithm."
+    // Send INIT (level-triggered) interrupt to reset other CPU.
+    lapic_write(ICRHI, (apicid as u32) << 24);
+    lapic_write(ICRLO, INIT | LEVEL | ASSERT);
+    microdelay(200);
+    lapic_write(ICRLO, INIT | LEVEL);
+    microdelay(100); // should be 10ms, but too slow in Bochs!
+
+    // Send startup IPI (twice!) to enter code.
+    // Regular hardware is supposed to only accept a STARTUP
+    // when it is in the halted state due to an INIT.  So the second
+    // should be ignored, but it is part of the official Intel algorithm.
+    // Bochs complains about the second one.  Too bad for Bochs.
+    for _ in 0..2 {
+        lapic_write(ICRHI, (apicid as u32) << 24);
+        lapic_write(ICRLO, STARTUP | (addr.as_raw() >> 12) as u32);
+        microdelay(200);
+    }
+}
 
 pub unsafe fn lapicid() -> u8 {
     if lapic.is_null() {
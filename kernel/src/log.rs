@@ -0,0 +1,326 @@
+// Simple logging that allows concurrent FS system calls.
+//
+// A log transaction contains the updates of multiple FS system
+// calls. The logging system only commits when there are
+// no FS system calls active. Thus there is never
+// any reasoning required about whether a commit might
+// write an uncommitted system call's updates to disk.
+//
+// A system call should call begin_op()/end_op() to mark
+// its start and end. Usually begin_op() just increments
+// the count of in-progress FS system calls and returns.
+// But if it thinks the log is close to running out, it
+// sleeps until the last outstanding end_op() commits.
+//
+// The log is a physical re-do log containing disk blocks.
+// The on-disk log format:
+//   header block, containing block #s for block A, B, C, ...
+//   block A
+//   block B
+//   block C
+//   ...
+// Log appends are synchronous.
+
+use super::bio::{self, BufGuard};
+use super::buf::BufFlags;
+use super::fs::{self, BSIZE};
+use super::param::{LOGSIZE, MAXOPBLOCKS};
+use super::proc;
+use super::spinlock::SpinLock;
+use super::utils::address::vaddr;
+
+// Contents of the header block, used for both the on-disk header block
+// and to keep track in memory of logged block# before commit.
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct logheader {
+    n: u32,
+    block: [u32; LOGSIZE],
+}
+
+struct Log {
+    start: u32,
+    size: u32,
+    outstanding: u32, // how many FS sys calls are executing.
+    committing: bool, // in commit(), please wait.
+    dev: u32,
+    lh: logheader,
+}
+
+static log: SpinLock<Log> = SpinLock::new(
+    "log",
+    Log {
+        start: 0,
+        size: 0,
+        outstanding: 0,
+        committing: false,
+        dev: 0,
+        lh: logheader {
+            n: 0,
+            block: [0; LOGSIZE],
+        },
+    },
+);
+
+// Processes waiting for the log sleep on its address.
+fn chan() -> vaddr {
+    vaddr::from_ptr(&log as *const SpinLock<Log>).unwrap()
+}
+
+pub fn initlog(dev: u32) {
+    if core::mem::size_of::<logheader>() >= BSIZE {
+        panic!("initlog: too big logheader");
+    }
+
+    let sb = fs::getsb();
+    {
+        let mut l = log.lock();
+        l.start = sb.logstart;
+        l.size = sb.nlog;
+        l.dev = dev;
+    }
+    recover_from_log(dev, sb.logstart);
+}
+
+// Copy committed blocks from log to their home location
+fn install_trans(dev: u32, start: u32, lh: &logheader) {
+    for tail in 0..lh.n {
+        let lbuf = bio::bread(dev, start + tail + 1); // read log block
+        let mut dbuf = bio::bread(dev, lh.block[tail as usize]); // read dst
+        dbuf.data.copy_from_slice(&lbuf.data); // copy block to dst
+        bio::bwrite(&mut dbuf); // write dst to disk
+    }
+}
+
+// Read the log header from disk into the in-memory log header
+fn read_head(dev: u32, start: u32) -> logheader {
+    let buf = bio::bread(dev, start);
+    unsafe { core::ptr::read_unaligned(buf.data.as_ptr() as *const logheader) }
+}
+
+// Write in-memory log header to disk.
+// This is the true point at which the
+// current transaction commits.
+fn write_head(dev: u32, start: u32, lh: &logheader) {
+    let mut buf = bio::bread(dev, start);
+    unsafe {
+        core::ptr::write_unaligned(buf.data.as_mut_ptr() as *mut logheader, *lh);
+    }
+    bio::bwrite(&mut buf);
+}
+
+fn recover_from_log(dev: u32, start: u32) {
+    let mut lh = read_head(dev, start);
+    install_trans(dev, start, &lh); // if committed, copy from log to disk
+    lh.n = 0;
+    write_head(dev, start, &lh); // clear the log
+}
+
+// called at the start of each FS system call.
+pub fn begin_op() {
+    let mut l = log.lock();
+    loop {
+        if l.committing {
+            l = proc::sleep(chan(), l);
+        } else if l.lh.n as usize + (l.outstanding as usize + 1) * MAXOPBLOCKS > LOGSIZE {
+            // this op might exhaust log space; wait for commit.
+            l = proc::sleep(chan(), l);
+        } else {
+            l.outstanding += 1;
+            break;
+        }
+    }
+}
+
+// called at the end of each FS system call.
+// commits if this was the last outstanding operation.
+pub fn end_op() {
+    let do_commit;
+    {
+        let mut l = log.lock();
+        l.outstanding -= 1;
+        if l.committing {
+            panic!("log.committing");
+        }
+        if l.outstanding == 0 {
+            do_commit = true;
+            l.committing = true;
+        } else {
+            // begin_op() may be waiting for log space,
+            // and decrementing log.outstanding has decreased
+            // the amount of reserved space.
+            do_commit = false;
+            proc::wakeup(chan());
+        }
+    }
+
+    if do_commit {
+        // call commit w/o holding locks, since not allowed
+        // to sleep with locks.
+        commit();
+        let mut l = log.lock();
+        l.committing = false;
+        proc::wakeup(chan());
+    }
+}
+
+// Copy modified blocks from cache to log.
+fn write_log(dev: u32, start: u32, lh: &logheader) {
+    for tail in 0..lh.n {
+        let mut to = bio::bread(dev, start + tail + 1); // log block
+        let from = bio::bread(dev, lh.block[tail as usize]); // cache block
+        to.data.copy_from_slice(&from.data);
+        drop(from);
+        bio::bwrite(&mut to); // write the log
+    }
+}
+
+// Nobody else touches the header while committing is set,
+// so it is safe to work on a copy of it.
+fn commit() {
+    let (dev, start, mut lh) = {
+        let l = log.lock();
+        (l.dev, l.start, l.lh)
+    };
+    if lh.n > 0 {
+        write_log(dev, start, &lh); // Write modified blocks from cache to log
+        write_head(dev, start, &lh); // Write header to disk -- the real commit
+        install_trans(dev, start, &lh); // Now install writes to home locations
+        lh.n = 0;
+        log.lock().lh.n = 0;
+        write_head(dev, start, &lh); // Erase the transaction from the log
+    }
+}
+
+// Caller has modified b.data and is done with the buffer.
+// Record the block number and pin in the cache with DIRTY.
+// commit()/write_log() will do the disk write.
+//
+// log_write() replaces bwrite(); a typical use is:
+//   let mut bp = bio::bread(...);
+//   modify bp.data[]
+//   log_write(&mut bp);
+//   drop(bp);
+pub fn log_write(b: &mut BufGuard) {
+    let mut l = log.lock();
+    let n = l.lh.n as usize;
+    if n >= LOGSIZE || n as u32 >= l.size - 1 {
+        panic!("too big a transaction");
+    }
+    if l.outstanding < 1 {
+        panic!("log_write outside of trans");
+    }
+
+    // log absorption
+    let i = l.lh.block[..n]
+        .iter()
+        .position(|&blockno| blockno == b.blockno)
+        .unwrap_or(n);
+    l.lh.block[i] = b.blockno;
+    if i == n {
+        l.lh.n += 1;
+    }
+    b.flags.insert(BufFlags::DIRTY); // prevent eviction
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::param::ROOTDEV;
+    use crate::ramdisk::{self, RAMDISK};
+    use crate::utils;
+
+    const LOGSTART: u32 = 2;
+
+    // Put a superblock describing just a log on the RAM disk and
+    // start the log from it, like iinit() and initlog() at boot.
+    fn setup() {
+        let sb = fs::superblock {
+            size: ramdisk::NBLOCKS as u32,
+            nblocks: 0,
+            ninodes: 0,
+            nlog: LOGSIZE as u32 + 1,
+            logstart: LOGSTART,
+            inodestart: 0,
+            bmapstart: 0,
+        };
+        {
+            let mut b = bio::bread(ROOTDEV, 1);
+            unsafe {
+                core::ptr::write_unaligned(b.data.as_mut_ptr() as *mut fs::superblock, sb);
+            }
+            bio::bwrite(&mut b);
+        }
+        fs::iinit(ROOTDEV);
+        initlog(ROOTDEV);
+    }
+
+    // Write a block of byte c to the i'th log block, bypassing the log.
+    fn write_logblock(i: u32, c: u8) {
+        let mut b = bio::bread(ROOTDEV, LOGSTART + 1 + i);
+        utils::fill(&mut b.data, c);
+        bio::bwrite(&mut b);
+    }
+
+    fn disk_head() -> logheader {
+        let data = RAMDISK.read(LOGSTART);
+        unsafe { core::ptr::read_unaligned(data.as_ptr() as *const logheader) }
+    }
+
+    fn disk_block_is(blockno: u32, c: u8) -> bool {
+        RAMDISK.read(blockno).iter().all(|x| *x == c)
+    }
+
+    #[test_case]
+    fn commit_installs_logged_blocks() {
+        setup();
+        begin_op();
+        {
+            let mut b = bio::bread(ROOTDEV, 40);
+            utils::fill(&mut b.data, 0xaa);
+            log_write(&mut b);
+        }
+        assert!(disk_block_is(40, 0));
+        end_op();
+
+        assert!(disk_block_is(LOGSTART + 1, 0xaa));
+        assert!(disk_block_is(40, 0xaa));
+        assert_eq!(disk_head().n, 0);
+    }
+
+    #[test_case]
+    fn recovery_installs_committed_transaction() {
+        setup();
+        // As if we crashed in commit() after write_head():
+        // block 41's new contents are only in the log.
+        write_logblock(0, 0x55);
+        let mut lh = logheader {
+            n: 1,
+            block: [0; LOGSIZE],
+        };
+        lh.block[0] = 41;
+        write_head(ROOTDEV, LOGSTART, &lh);
+        assert!(disk_block_is(41, 0));
+
+        initlog(ROOTDEV);
+        assert!(disk_block_is(41, 0x55));
+        assert_eq!(disk_head().n, 0);
+    }
+
+    #[test_case]
+    fn recovery_ignores_uncommitted_transaction() {
+        setup();
+        // As if we crashed in commit() before write_head():
+        // the log block is written but the header doesn't count it.
+        write_logblock(0, 0x66);
+        let mut lh = logheader {
+            n: 0,
+            block: [0; LOGSIZE],
+        };
+        lh.block[0] = 42;
+        write_head(ROOTDEV, LOGSTART, &lh);
+
+        initlog(ROOTDEV);
+        assert!(disk_block_is(42, 0));
+    }
+}
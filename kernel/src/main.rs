@@ -5,6 +5,12 @@
 #![feature(asm)]
 #![feature(start)]
 #![feature(ptr_offset_from)]
+#![feature(const_fn)]
+#![feature(const_in_array_repeat_expressions)]
+#![feature(alloc_error_handler)]
+#![feature(custom_test_frameworks)]
+#![test_runner(crate::ktest::runner)]
+#![reexport_test_harness_main = "test_main"]
 
 //------------------------------------------------------------------------------
 
@@ -16,6 +22,10 @@ extern crate lazy_static;
 
 extern crate spin;
 
+extern crate elf;
+
+extern crate alloc;
+
 //------------------------------------------------------------------------------
 
 #[macro_use]
@@ -23,34 +33,58 @@ mod utils;
 #[macro_use]
 mod vga_buffer;
 
+mod bio;
+mod buf;
 mod console;
+mod exec;
+mod fcntl;
 mod file;
 mod fs;
+mod heap;
+mod ide;
 mod ioapic;
 mod kalloc;
+mod kbd;
+#[cfg(test)]
+mod ktest;
 mod lapic;
+mod log;
+mod mmap;
 mod mmu;
 mod mp;
 mod param;
 mod picirq;
 mod pipe;
 mod proc;
+#[cfg(test)]
+mod ramdisk;
+mod rtc;
+mod sleeplock;
+mod spinlock;
+mod stat;
+mod syscall;
+mod sysfile;
+mod sysproc;
 mod traps;
 mod uart;
 mod vm;
 mod x86;
 
-use utils::address::{p2v, paddr, v2p, vaddr};
+use utils::address::{p2v, paddr, paddr_raw, v2p, vaddr};
 
 //------------------------------------------------------------------------------
 
 global_asm!(include_str!("entry.S"));
+global_asm!(include_str!("entryother.S"));
 
 type PageDirEntry = u32;
 
 extern "C" {
     #[no_mangle]
     static kernel_end: [u8; 0];
+
+    static entryother_start: [u8; 0];
+    static entryother_end: [u8; 0];
 }
 
 #[used]
@@ -83,7 +117,7 @@ pub extern "C" fn main() {
     mp::mp_init();
 
     // interrupt controller
-    lapic::lapic_init();
+    lapic::lapic_init(param::HZ);
 
     // segment descriptors
     vm::seg_init();
@@ -98,7 +132,7 @@ pub extern "C" fn main() {
     console::console_init();
 
     // serial port
-    uart::uart_init();
+    uart::uart_init(param::BAUD);
 
     // process table
     proc::pinit();
@@ -106,9 +140,95 @@ pub extern "C" fn main() {
     // trap vectors
     traps::tvinit();
 
-    unimplemented!();
+    // wall clock
+    rtc::rtc_init();
 
-    loop {}
+    // buffer cache
+    #[cfg(not(test))]
+    bio::binit(&ide::IDEDISK);
+    #[cfg(test)]
+    bio::binit(&ramdisk::RAMDISK);
+
+    // disk
+    ide::ideinit();
+
+    // start other processors
+    startothers();
+
+    // must come after startothers()
+    kalloc::kinit2(
+        p2v(paddr::from_raw(4 * 1024 * 1024).unwrap()),
+        p2v(paddr::from_raw(vm::PHYSTOP).unwrap()),
+    );
+    // kernel heap locking; like kinit2(), after startothers()
+    heap::heapinit();
+
+    // first user process
+    proc::userinit();
+
+    // finish this processor's setup
+    mpmain();
+}
+
+// Other CPUs jump here from entryother.S.
+extern "C" fn mpenter() {
+    vm::switchkvm();
+    vm::seg_init();
+    lapic::lapic_init(param::HZ);
+    mpmain();
+}
+
+// Common CPU setup code.
+fn mpmain() -> ! {
+    let c = proc::mycpu();
+    println!("cpu{}: starting", c.cpuid());
+    traps::idtinit(); // load idt register
+    unsafe {
+        // tell startothers() we're up
+        core::ptr::write_volatile(&mut c.started, true);
+    }
+    proc::scheduler(); // start running processes
+}
+
+// Start the non-boot (AP) processors.
+fn startothers() {
+    // Write entry code to unused memory at 0x7000.
+    // The linker has placed the image of entryother.S in
+    // the kernel's text segment.
+    let code = p2v(paddr_raw(0x7000));
+    unsafe {
+        let size = entryother_end
+            .as_ptr()
+            .offset_from(entryother_start.as_ptr()) as usize;
+        core::ptr::copy(entryother_start.as_ptr(), code.as_mut_ptr::<u8>(), size);
+    }
+
+    let me = proc::mycpu().cpuid();
+    for i in 0..unsafe { mp::CPU_ARRAY.len() } {
+        let c = unsafe { mp::CPU_ARRAY.borrow_mut(i) };
+        if c.cpuid() == me {
+            // We've started already.
+            continue;
+        }
+
+        // Tell entryother.S what stack to use, where to enter, and what
+        // pgdir to use. We cannot use kpgdir yet, because the AP processor
+        // is running in low  memory, so we use entrypgdir for the APs too.
+        let stack = kalloc::kalloc().expect("startothers: out of memory");
+        unsafe {
+            let args = code.as_mut_ptr::<usize>();
+            *args.sub(1) = stack.as_ptr() as usize + param::KSTACKSIZE;
+            *args.sub(2) = mpenter as usize;
+            *args.sub(3) = v2p(vaddr::from_ptr(entrypgdir.as_ptr()).unwrap()).as_raw();
+        }
+
+        lapic::lapic_startap(c.apicid, v2p(code));
+
+        // wait for cpu to finish mpmain()
+        while !unsafe { core::ptr::read_volatile(&c.started) } {
+            x86::nop();
+        }
+    }
 }
 
 use core::panic::PanicInfo;
@@ -117,6 +237,8 @@ use core::panic::PanicInfo;
 #[no_mangle]
 fn panic(info: &PanicInfo) -> ! {
     println!(vga_buffer::ERROR_COLOR; "{}", info);
+    #[cfg(test)]
+    ktest::fail(info);
     loop {}
 }
 
@@ -0,0 +1,480 @@
+//
+// Memory-mapped regions: mmap() and munmap().
+// Each process has a small table of virtual memory areas,
+// placed top-down from MMAPTOP so that they stay above the heap.
+// Pages are faulted in lazily (see pgfault in traps.rs), and dirty
+// pages of shared file mappings are written back on munmap and exit.
+// All MAP_SHARED mappings of the same part of a file, in any process,
+// use the same physical page, found through the shpages table, and
+// writei() keeps those pages up to date with the file.
+//
+
+use super::fcntl::{MAP_ANONYMOUS, MAP_PRIVATE, MAP_SHARED, PROT_EXEC, PROT_READ, PROT_WRITE};
+use super::file::{FileRef, MAXWRITE};
+use super::kalloc;
+use super::log;
+use super::mmu::{self, Page, PteFlags, PGSIZE};
+use super::param::NSHPAGE;
+use super::proc;
+use super::spinlock::SpinLock;
+use super::stat::T_FILE;
+use super::utils;
+use super::utils::address::{p2v, paddr_pg, v2p, vaddr_pg, vaddr_raw};
+use super::vm::{self, PageDir};
+
+// Mappings are allocated downwards from here.
+pub const MMAPTOP: usize = vm::KERNBASE;
+
+// A mapped region [start, end) of a process's address space.
+#[derive(Clone)]
+pub struct Vma {
+    start: usize,          // First address, page aligned
+    end: usize,            // One past the last address, page aligned
+    prot: i32,             // PROT_* bits
+    flags: i32,            // MAP_* bits
+    file: Option<FileRef>, // Backing file, None if anonymous
+    off: usize,            // File offset of start
+}
+
+impl Vma {
+    fn shared(&self) -> bool {
+        self.flags & MAP_SHARED != 0
+    }
+}
+
+// A page of a file mapped MAP_SHARED.  The table holds a reference
+// to the page, and drops it once no mapping has the page mapped.
+#[derive(Clone, Copy)]
+struct SharedPage {
+    dev: u32,
+    inum: u32,
+    off: usize, // File offset of the page
+    pa: paddr_pg,
+}
+
+impl SharedPage {
+    // Mapped by no process, only held by the table.
+    fn unused(&self) -> bool {
+        kalloc::refcount(self.pa) == 1
+    }
+}
+
+static shpages: SpinLock<[Option<SharedPage>; NSHPAGE]> = SpinLock::new("shpages", {
+    const INIT: Option<SharedPage> = None;
+    [INIT; NSHPAGE]
+});
+
+// Lowest address used by any mapping of p.
+// The heap must not grow past it.
+pub fn mmapbase(p: &proc::proc) -> usize {
+    p.vma
+        .iter()
+        .flatten()
+        .map(|v| v.start)
+        .min()
+        .unwrap_or(MMAPTOP)
+}
+
+// Map len bytes of file, starting at offset off, into the current
+// process, or zero-filled memory if file is None.
+// Returns the address of the new mapping.
+pub fn mmap(
+    len: usize,
+    prot: i32,
+    flags: i32,
+    file: Option<FileRef>,
+    off: usize,
+) -> Result<usize, &'static str> {
+    let p = proc::myproc().unwrap();
+
+    if len == 0 || off % PGSIZE != 0 {
+        return Err("mmap: bad length or offset");
+    }
+    if prot & !(PROT_READ | PROT_WRITE | PROT_EXEC) != 0 {
+        return Err("mmap: bad protection");
+    }
+    let shared = flags & MAP_SHARED != 0;
+    if shared == (flags & MAP_PRIVATE != 0) {
+        return Err("mmap: need one of MAP_SHARED and MAP_PRIVATE");
+    }
+    match &file {
+        None => {
+            if flags & MAP_ANONYMOUS == 0 || shared {
+                return Err("mmap: anonymous mappings must be private");
+            }
+        }
+        Some(f) => {
+            if flags & MAP_ANONYMOUS != 0 {
+                return Err("mmap: anonymous mapping of a file");
+            }
+            if f.filestat()?.file_type != T_FILE {
+                return Err("mmap: not a regular file");
+            }
+            // Mapped pages can always be read, whatever prot says.
+            if !f.readable() {
+                return Err("mmap: file not readable");
+            }
+            if shared && prot & PROT_WRITE != 0 && !f.writable() {
+                return Err("mmap: file not writable");
+            }
+        }
+    }
+    let len = len.checked_add(PGSIZE - 1).ok_or("mmap: too large")? & !(PGSIZE - 1);
+
+    let slot = p
+        .vma
+        .iter()
+        .position(Option::is_none)
+        .ok_or("mmap: too many mappings")?;
+
+    // Find the highest hole that fits above the heap.
+    let mut end = MMAPTOP;
+    let start = loop {
+        let start = match end.checked_sub(len) {
+            Some(start) if start >= p.sz => start,
+            _ => return Err("mmap: out of address space"),
+        };
+        let below = p
+            .vma
+            .iter()
+            .flatten()
+            .filter(|v| v.start < end && start < v.end)
+            .map(|v| v.start)
+            .min();
+        match below {
+            Some(s) => end = s,
+            None => break start,
+        }
+    };
+
+    p.vma[slot] = Some(Vma {
+        start,
+        end: start + len,
+        prot,
+        flags,
+        file,
+        off,
+    });
+    Ok(start)
+}
+
+// Unmap [addr, addr+len) from the current process.  The range
+// may cover the start or the end of a mapping, or all of it,
+// but must not punch a hole in the middle.
+pub fn munmap(addr: usize, len: usize) -> Result<(), &'static str> {
+    let p = proc::myproc().unwrap();
+
+    if addr % PGSIZE != 0 || len == 0 {
+        return Err("munmap: bad address or length");
+    }
+    let end = addr
+        .checked_add(len)
+        .and_then(|end| end.checked_add(PGSIZE - 1))
+        .ok_or("munmap: bad length")?
+        & !(PGSIZE - 1);
+
+    let i = p
+        .vma
+        .iter()
+        .position(|v| {
+            v.as_ref()
+                .map_or(false, |v| v.start <= addr && addr < v.end)
+        })
+        .ok_or("munmap: not mapped")?;
+    {
+        let v = p.vma[i].as_mut().unwrap();
+        if end > v.end {
+            return Err("munmap: range spans several mappings");
+        }
+        if addr != v.start && end != v.end {
+            return Err("munmap: would split a mapping");
+        }
+
+        unmaprange(p.pgdir.as_mut().unwrap(), v, addr, end)?;
+        if addr == v.start {
+            v.off += end - addr;
+            v.start = end;
+        } else {
+            v.end = addr;
+        }
+    }
+    if p.vma[i].as_ref().map_or(false, |v| v.start == v.end) {
+        p.vma[i] = None; // closes the file
+    }
+    vm::switchuvm(p);
+    Ok(())
+}
+
+// Remove all of p's mappings, writing back dirty shared pages.
+// Used by exit and exec.
+pub fn unmapall(p: &mut proc::proc) {
+    for slot in p.vma.iter_mut() {
+        if let Some(v) = slot {
+            let (start, end) = (v.start, v.end);
+            let pgdir = p.pgdir.as_mut().unwrap();
+            // Nobody is left to report the error to.
+            if let Err(e) = unmaprange(pgdir, v, start, end) {
+                println!("pid {}: {}: mapped data lost", p.pid, e);
+                droprange(pgdir, v, start, end);
+            }
+            *slot = None;
+        }
+    }
+    vm::switchuvm(p);
+}
+
+// Give the new child np the same mappings as p.
+// Shared mappings keep sharing their pages; private ones
+// are copied on write, like the rest of the address space.
+pub fn mmapfork(p: &mut proc::proc, np: &mut proc::proc) -> Result<(), &'static str> {
+    let pgdir = p.pgdir.as_mut().unwrap();
+    let d = np.pgdir.as_mut().unwrap();
+    for (nv, v) in np.vma.iter_mut().zip(p.vma.iter()) {
+        if let Some(v) = v {
+            vm::copyrange(pgdir, d, v.start, v.end, v.shared()).ok_or("fork: out of memory")?;
+            *nv = Some(v.clone());
+        }
+    }
+    Ok(())
+}
+
+// Fault in the page at va if it lies in one of p's mappings.
+pub fn mmapfault(p: &mut proc::proc, va: usize, write: bool) -> Result<(), &'static str> {
+    let v = p
+        .vma
+        .iter()
+        .flatten()
+        .find(|v| v.start <= va && va < v.end)
+        .ok_or("access outside process memory")?;
+    if write && v.prot & PROT_WRITE == 0 {
+        return Err("write to read-only mapping");
+    }
+    let a = mmu::page_rounddown(vaddr_raw(va));
+    let off = v.off + (a.as_raw() - v.start);
+    let pa = match &v.file {
+        Some(f) if v.shared() => sharedpage(f, off)?,
+        f => {
+            let mem = readpage(f.as_ref(), off)?;
+            v2p(vaddr_pg::from_ptr(mem.as_ptr()).unwrap())
+        }
+    };
+
+    let mut perm = PteFlags::USER;
+    if v.prot & PROT_WRITE != 0 {
+        perm |= PteFlags::WRITABLE;
+    }
+    let pgdir = p.pgdir.as_mut().unwrap();
+    if vm::mappages(pgdir, a.into(), PGSIZE, pa, perm).is_none() {
+        kalloc::kfree(page(pa));
+        return Err("mmap: out of memory");
+    }
+    Ok(())
+}
+
+// A new page holding the bytes of f at off, zero-filled
+// past the end of the file, or a zeroed page if f is None.
+fn readpage(f: Option<&FileRef>, off: usize) -> Result<&'static mut Page, &'static str> {
+    let mem = kalloc::kalloc().ok_or("mmap: out of memory")?;
+    utils::fill(&mut mem[..], 0);
+    if let Some(f) = f {
+        let mut ip = f.inode().unwrap().ilock();
+        if off < ip.size {
+            let n = core::cmp::min(PGSIZE, ip.size - off);
+            if let Err(e) = ip.readi(&mut mem[..n], off) {
+                kalloc::kfree(mem);
+                return Err(e);
+            }
+        }
+    }
+    Ok(mem)
+}
+
+// Return the shared page of f at off, reading it in if no
+// mapping has it yet.  The caller gets a reference to it.
+fn sharedpage(f: &FileRef, off: usize) -> Result<paddr_pg, &'static str> {
+    let ip = f.inode().unwrap();
+    let find = |t: &[Option<SharedPage>]| {
+        t.iter()
+            .flatten()
+            .find(|s| s.dev == ip.dev && s.inum == ip.inum && s.off == off)
+            .map(|s| s.pa)
+    };
+    let share = |pa| {
+        kalloc::incref(pa)
+            .map(|_| pa)
+            .ok_or("mmap: page shared too often")
+    };
+    {
+        let t = shpages.lock();
+        if let Some(pa) = find(&t[..]) {
+            // The table's reference keeps the page while t is held.
+            return share(pa);
+        }
+    }
+
+    // Read the page without holding the lock, then check
+    // that no other process read it in meanwhile.
+    let mem = readpage(Some(f), off)?;
+    let mut t = shpages.lock();
+    if let Some(pa) = find(&t[..]) {
+        kalloc::kfree(mem);
+        return share(pa);
+    }
+    let slot = match t.iter_mut().find(|s| s.map_or(true, |s| s.unused())) {
+        Some(slot) => slot,
+        None => {
+            kalloc::kfree(mem);
+            return Err("mmap: too many shared pages");
+        }
+    };
+    if let Some(s) = slot.take() {
+        kalloc::kfree(page(s.pa));
+    }
+    let pa = v2p(vaddr_pg::from_ptr(mem.as_ptr()).unwrap());
+    kalloc::incref(pa).unwrap(); // one for the table, one for the caller
+    *slot = Some(SharedPage {
+        dev: ip.dev,
+        inum: ip.inum,
+        off,
+        pa,
+    });
+    Ok(pa)
+}
+
+// Drop the shared pages that are no longer mapped anywhere.
+fn shrelease() {
+    for slot in shpages.lock().iter_mut() {
+        if slot.map_or(false, |s| s.unused()) {
+            kalloc::kfree(page(slot.take().unwrap().pa));
+        }
+    }
+}
+
+// Copy src, just written to the inode dev/inum at off by writei,
+// into the shared pages that cover it, so that mappings see the new
+// bytes and don't write older ones back over them later.
+pub fn shwrite(dev: u32, inum: u32, src: &[u8], off: usize) {
+    let end = off + src.len();
+    for s in shpages.lock().iter().flatten() {
+        if s.dev != dev || s.inum != inum {
+            continue;
+        }
+        let lo = core::cmp::max(off, s.off);
+        let hi = core::cmp::min(end, s.off + PGSIZE);
+        if lo >= hi {
+            continue;
+        }
+        let from = unsafe { src.as_ptr().add(lo - off) };
+        let to = unsafe { p2v(s.pa).as_mut_ptr::<u8>().add(lo - s.off) };
+        // src may lie in a shared page itself, even this one
+        // when writeback() writes it, so the two may overlap.
+        if from != to as *const u8 {
+            unsafe { core::ptr::copy(from, to, hi - lo) };
+        }
+    }
+}
+
+// Forget the shared pages of the inode dev/inum, which itrunc
+// is discarding; its inode number may be reused for another file.
+pub fn shtrunc(dev: u32, inum: u32) {
+    for slot in shpages.lock().iter_mut() {
+        if slot.map_or(false, |s| s.dev == dev && s.inum == inum) {
+            kalloc::kfree(page(slot.take().unwrap().pa));
+        }
+    }
+}
+
+// The kernel's view of the physical page at pa.
+fn page(pa: paddr_pg) -> &'static mut Page {
+    unsafe { &mut *p2v(pa).as_mut_ptr::<Page>() }
+}
+
+// Make sure the kernel can access [start, end) of p's mappings
+// without faulting: the range must lie in one mapping, writable if
+// write is set, and its pages are faulted in now, before the caller
+// takes any locks.  Private pages shared since fork() are copied
+// here too if the kernel is going to write to them.
+pub fn prefault(
+    p: &mut proc::proc,
+    start: usize,
+    end: usize,
+    write: bool,
+) -> Result<(), &'static str> {
+    let v = p
+        .vma
+        .iter()
+        .flatten()
+        .find(|v| v.start <= start && end <= v.end)
+        .ok_or("user address out of range")?;
+    if write && v.prot & PROT_WRITE == 0 {
+        return Err("write to read-only mapping");
+    }
+
+    let mut a = start & !(PGSIZE - 1);
+    while a < end {
+        let va = vaddr_pg::from_raw(a).unwrap();
+        let present = vm::walkpgdir(p.pgdir.as_mut().unwrap(), va, false)
+            .map_or(false, |pte| *pte & PteFlags::PRESENT.bits() != 0);
+        if !present {
+            mmapfault(p, a, write)?;
+        }
+        a += PGSIZE;
+    }
+    // Nothing is missing now, so this only does copy-on-write.
+    vm::prefault(p.pgdir.as_mut().unwrap(), start, end, write)
+}
+
+// Write back the dirty pages of v in [start, end) if it is a
+// shared file mapping, then unmap them.  If writing back fails,
+// the range is left mapped so that its data isn't lost.
+fn unmaprange(pgdir: &mut PageDir, v: &Vma, start: usize, end: usize) -> Result<(), &'static str> {
+    let writable = v.shared() && v.prot & PROT_WRITE != 0;
+    if let Some(f) = v.file.as_ref().filter(|_| writable) {
+        let mut a = start;
+        while a < end {
+            let va = vaddr_pg::from_raw(a).unwrap();
+            let dirty = vm::walkpgdir(pgdir, va, false).map_or(false, |pte| {
+                let flags = mmu::pte_flags(*pte);
+                flags.contains(PteFlags::PRESENT | PteFlags::DIRTY)
+            });
+            if dirty {
+                let page = vm::uva2ka(pgdir, a).unwrap();
+                let src = unsafe { core::slice::from_raw_parts(page, PGSIZE) };
+                writeback(f, src, v.off + (a - v.start))?;
+            }
+            a += PGSIZE;
+        }
+    }
+    droprange(pgdir, v, start, end);
+    Ok(())
+}
+
+// Unmap [start, end) of v without writing anything back.
+fn droprange(pgdir: &mut PageDir, v: &Vma, start: usize, end: usize) {
+    vm::deallocuvm(pgdir, end, start);
+    if v.shared() && v.file.is_some() {
+        shrelease();
+    }
+}
+
+// Write src back to f at off.  Mapped pages may extend past
+// the end of the file; that part is dropped.
+fn writeback(f: &FileRef, src: &[u8], off: usize) -> Result<(), &'static str> {
+    let ip = f.inode().unwrap();
+    let size = ip.ilock().size;
+    if off >= size {
+        return Ok(());
+    }
+    let src = &src[..core::cmp::min(src.len(), size - off)];
+
+    let mut i = 0;
+    while i < src.len() {
+        let n1 = core::cmp::min(src.len() - i, MAXWRITE);
+        log::begin_op();
+        let r = ip.ilock().writei(&src[i..i + n1], off + i);
+        log::end_op();
+        r?;
+        i += n1;
+    }
+    Ok(())
+}
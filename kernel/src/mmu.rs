@@ -65,7 +65,34 @@ impl SegDesc {
                 | (lim >> 12 & 0xffff),
         )
     }
+    // segment descriptor with byte granularity (used for the TSS)
+    pub fn new16(ty: u8, base: u32, lim: u32, dpl: u8) -> Self {
+        let ty = ty as u64;
+        let base = base as u64;
+        let lim = lim as u64;
+        let dpl = dpl as u64;
+        SegDesc(
+            (((base >> 24) & 0xff) << 56)
+                | (0 << 55)
+                | (1 << 54)
+                | (0 << 53)
+                | (0 << 52)
+                | (((lim >> 16) & 0x0f) << 48)
+                | (1 << 47)
+                | ((dpl & 0x03) << 45)
+                | (1 << 44)
+                | ((ty & 0x0f) << 40)
+                | (((base >> 16) & 0xff) << 32)
+                | ((base & 0xffff) << 16)
+                | (lim & 0xffff),
+        )
+    }
+
     // functions to read / write segment descriptor
+    pub fn set_s(&mut self, s: bool) {
+        const mask: u64 = !(0x01 << 44);
+        self.0 = (self.0 & mask) | (s as u64) << 44;
+    }
 }
 
 // Task state segment format
@@ -73,8 +100,8 @@ impl SegDesc {
 #[repr(C)]
 pub struct taskstate {
     link: u32,        // Old ts selector
-    esp0: u32,        // Stack pointers and segment selectors after an increase in privilege level
-    ss0: u16,         //
+    pub esp0: u32,    // Stack pointers and segment selectors after an increase in privilege level
+    pub ss0: u16,     //
     padding1: u16,    //
     esp1: *const u32, //
     ss1: u16,         //
@@ -108,7 +135,7 @@ pub struct taskstate {
     ldt: u16,         //
     padding10: u16,   //
     t: u16,           // Trap on task switch
-    iomb: u16,        // I/O map base address
+    pub iomb: u16,    // I/O map base address
 }
 impl taskstate {
     pub fn new() -> Self {
@@ -238,7 +265,9 @@ bitflags! {
         const PRESENT = 0x001; // Present
         const WRITABLE = 0x002; // Writeable
         const USER = 0x004; // User
+        const DIRTY = 0x040; // Dirty (set by the hardware on write)
         const PAGE_SIZE = 0x080; // Page Size
+        const COW = 0x200; // Copy-on-write (available to software)
     }
 }
 
@@ -248,7 +277,7 @@ pub fn pte_addr(pte: u32) -> paddr_pg {
 }
 #[inline]
 pub fn pte_flags(pte: u32) -> PteFlags {
-    PteFlags::from_bits(pte & 0xFFF).unwrap()
+    PteFlags::from_bits_truncate(pte)
 }
 
 // page directory index
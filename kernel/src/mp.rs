@@ -1,5 +1,6 @@
+use alloc::vec::Vec;
+
 use super::lapic;
-use super::param;
 use super::proc::CPU;
 use super::utils;
 use super::utils::address::{p2v, paddr, paddr_raw, v2p, vaddr, vaddr_raw};
@@ -60,36 +61,34 @@ struct mpioapic {
 }
 
 // be careful to use !
+// Only grows in mp_init(), before any other cpu runs or
+// anything holds a reference to one of its CPUs.
 pub static mut CPU_ARRAY: CPUArray = CPUArray::new();
 pub static mut ioapicid: u8 = 0;
 
 #[derive(Debug)]
 pub struct CPUArray {
-    ncpu: usize,
-    cpus: [Option<CPU>; param::NCPU],
+    cpus: Vec<CPU>,
 }
 impl CPUArray {
     pub const fn new() -> Self {
-        CPUArray {
-            ncpu: 0,
-            cpus: [None, None, None, None, None, None, None, None],
-        }
+        CPUArray { cpus: Vec::new() }
     }
-    pub fn slice(&self) -> &[Option<CPU>] {
+    pub fn slice(&self) -> &[CPU] {
         &self.cpus
     }
     pub fn len(&self) -> usize {
-        self.ncpu
+        self.cpus.len()
     }
-    pub fn add(&mut self, id: usize, apicid: u8) {
-        self.cpus[self.ncpu] = Some(CPU::new(id, apicid));
-        self.ncpu += 1;
+    pub fn add(&mut self, apicid: u8) {
+        let id = self.cpus.len();
+        self.cpus.push(CPU::new(id, apicid));
     }
     pub fn borrow(&self, idx: usize) -> &CPU {
-        self.cpus[idx].as_ref().unwrap()
+        &self.cpus[idx]
     }
     pub fn borrow_mut(&mut self, idx: usize) -> &mut CPU {
-        self.cpus[idx].as_mut().unwrap()
+        &mut self.cpus[idx]
     }
 }
 
@@ -196,16 +195,11 @@ pub fn mp_init() {
         assert!(!p.is_null());
 
         use core::mem::size_of;
-        let mut ncpu = 0;
         while p.get() < e {
             match *p.get() {
                 MPPROC => {
                     let proc: Ptr<mpproc> = p.cast();
-                    if ncpu < param::NCPU {
-                        let apicid = (*proc.get()).apicid;
-                        CPU_ARRAY.add(ncpu, apicid);
-                        ncpu += 1;
-                    }
+                    CPU_ARRAY.add((*proc.get()).apicid);
                     p.increase_bytes(size_of::<mpproc>()).unwrap();
                 }
                 MPIOAPIC => {
@@ -238,12 +232,7 @@ pub fn mp_init() {
     println!("ncpu = {}", unsafe { CPU_ARRAY.len() });
     unsafe {
         for c in CPU_ARRAY.slice().iter() {
-            match c.as_ref() {
-                Some(c) => {
-                    println!("cpuid = {}, apicid = {}", c.id, c.apicid);
-                }
-                None => {}
-            }
+            println!("cpuid = {}, apicid = {}", c.id, c.apicid);
         }
     }
     println!("lapic = {:?}", unsafe { lapic::lapic });
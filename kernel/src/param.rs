@@ -1,4 +1,19 @@
-pub const NCPU: usize = 8; // maximum number of CPUs
+pub const NPROC: usize = 64; // maximum number of processes
+pub const KSTACKSIZE: usize = 4096; // size of per-process kernel stack
 pub const NOFILE: usize = 16; // open files per process
+pub const NVMA: usize = 16; // mmap()ed regions per process
+pub const NSHPAGE: usize = 256; // pages of MAP_SHARED file mappings per system
+pub const NFILE: usize = 100; // open files per system
+pub const MAXARG: usize = 32; // max exec arguments
+
+pub const NINODE: usize = 50; // maximum number of active i-nodes
+pub const ROOTDEV: u32 = 1; // device number of file system root disk
+pub const MAXOPBLOCKS: usize = 10; // max # of blocks any FS op writes
+pub const LOGSIZE: usize = MAXOPBLOCKS * 3; // max data blocks in on-disk log
+pub const NBUF: usize = MAXOPBLOCKS * 3; // size of disk block cache
+pub const HZ: u32 = 100; // timer interrupts per second
+pub const BAUD: u32 = 115200; // serial console baud rate
+
+pub const FSSIZE: usize = 1000; // size of file system in blocks
 
 pub const PIPESIZE: usize = 512;
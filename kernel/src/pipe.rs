@@ -1,6 +1,12 @@
-use super::param;
+use core::ops::Deref;
 
-use spin::Mutex;
+use super::file::{self, FileRef, FileType};
+use super::kalloc;
+use super::mmu;
+use super::param;
+use super::proc;
+use super::spinlock::SpinLock;
+use super::utils::address::vaddr;
 
 struct PipeContent {
     data: [u8; param::PIPESIZE],
@@ -8,8 +14,140 @@ struct PipeContent {
     nwrite: usize,   // number of bytes written
     readopen: bool,  // read fd is still open
     writeopen: bool, // write fd is still open
+    refs: usize,     // number of PipeRefs
 }
 
 pub struct Pipe {
-    content: Mutex<PipeContent>,
+    content: SpinLock<PipeContent>,
+}
+
+// A counted reference to a pipe, held by each end's file.
+// The pipe lives in its own page, which is freed when
+// the last PipeRef is dropped.
+pub struct PipeRef {
+    p: *const Pipe, // valid as long as refs > 0
+}
+
+// The pipe's content is protected by its own lock.
+unsafe impl Send for PipeRef {}
+
+// Allocate a pipe in its own page and return the read and write ends.
+pub fn pipealloc() -> Option<(FileRef, FileRef)> {
+    let page = kalloc::kalloc()?;
+    let p = page.as_mut_ptr() as *mut Pipe;
+    unsafe {
+        core::ptr::write(
+            p,
+            Pipe {
+                content: SpinLock::new(
+                    "pipe",
+                    PipeContent {
+                        data: [0; param::PIPESIZE],
+                        nread: 0,
+                        nwrite: 0,
+                        readopen: true,
+                        writeopen: true,
+                        refs: 2,
+                    },
+                ),
+            },
+        );
+    }
+    let p = p as *const Pipe;
+
+    // On failure, dropping whatever was allocated frees the pipe.
+    let rf = file::filealloc(FileType::FD_PIPE(PipeRef { p }), true, false);
+    let wf = file::filealloc(FileType::FD_PIPE(PipeRef { p }), false, true);
+    match (rf, wf) {
+        (Some(rf), Some(wf)) => Some((rf, wf)),
+        _ => None,
+    }
+}
+
+// Close one end of the pipe.
+// The pipe itself goes away with the last PipeRef.
+pub fn pipeclose(p: &Pipe, writable: bool) {
+    let mut c = p.content.lock();
+    if writable {
+        c.writeopen = false;
+        proc::wakeup(p.nread_chan());
+    } else {
+        c.readopen = false;
+        proc::wakeup(p.nwrite_chan());
+    }
+}
+
+impl Deref for PipeRef {
+    type Target = Pipe;
+    fn deref(&self) -> &Pipe {
+        unsafe { &*self.p }
+    }
+}
+
+impl Drop for PipeRef {
+    fn drop(&mut self) {
+        let last = {
+            let mut c = self.content.lock();
+            c.refs -= 1;
+            c.refs == 0
+        };
+        if last {
+            let page = unsafe { &mut *(self.p as *mut mmu::Page) };
+            kalloc::kfree(page);
+        }
+    }
+}
+
+impl Pipe {
+    // Readers sleep on the address of nread, writers on nwrite.
+    fn nread_chan(&self) -> vaddr {
+        vaddr::from_ptr(unsafe { &(*self.content.as_mut_ptr()).nread } as *const usize).unwrap()
+    }
+    fn nwrite_chan(&self) -> vaddr {
+        vaddr::from_ptr(unsafe { &(*self.content.as_mut_ptr()).nwrite } as *const usize).unwrap()
+    }
+
+    pub fn pipewrite(&self, src: &[u8]) -> Result<usize, &'static str> {
+        let mut c = self.content.lock();
+        for &byte in src {
+            while c.nwrite == c.nread + param::PIPESIZE {
+                // pipewrite-full
+                if !c.readopen || proc::myproc().map_or(true, |p| p.killed) {
+                    return Err("pipewrite: broken pipe");
+                }
+                proc::wakeup(self.nread_chan());
+                c = proc::sleep(self.nwrite_chan(), c); // pipewrite-sleep
+            }
+            let i = c.nwrite % param::PIPESIZE;
+            c.data[i] = byte;
+            c.nwrite += 1;
+        }
+        proc::wakeup(self.nread_chan()); // pipewrite-wakeup1
+        Ok(src.len())
+    }
+
+    // Blocks while the pipe is empty; returns 0 (EOF) once
+    // it is empty and the write end has been closed.
+    pub fn piperead(&self, dst: &mut [u8]) -> Result<usize, &'static str> {
+        let mut c = self.content.lock();
+        while c.nread == c.nwrite && c.writeopen {
+            // pipe-empty
+            if proc::myproc().map_or(true, |p| p.killed) {
+                return Err("piperead: killed");
+            }
+            c = proc::sleep(self.nread_chan(), c); // piperead-sleep
+        }
+        let mut i = 0;
+        while i < dst.len() {
+            // piperead-copy
+            if c.nread == c.nwrite {
+                break;
+            }
+            dst[i] = c.data[c.nread % param::PIPESIZE];
+            c.nread += 1;
+            i += 1;
+        }
+        proc::wakeup(self.nwrite_chan()); // piperead-wakeup
+        Ok(i)
+    }
 }
@@ -1,8 +1,16 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+
 use super::file;
+use super::fs;
+use super::kalloc;
 use super::lapic;
+use super::log;
+use super::mmap;
 use super::mmu;
 use super::mp;
 use super::param;
+use super::spinlock::{popcli, pushcli, SpinLock, SpinLockGuard};
+use super::utils;
 use super::utils::address::{vaddr, vaddr_raw};
 use super::vm;
 use super::x86::{self, EFlags};
@@ -11,13 +19,13 @@ use super::x86::{self, EFlags};
 pub struct CPU {
     pub id: usize,
     pub apicid: u8,                         // Local APIC ID
-    pub scheduler: *const context,          // swtch() here to enter scheduler
+    pub scheduler: *mut context,            // swtch() here to enter scheduler
     pub ts: mmu::taskstate,                 // Used by x86 to find stack for interrupt
     pub gdt: [mmu::SegDesc; mmu::seg::NUM], // x86 global descriptor table
     pub started: bool,                      // Has the CPU started?
     pub ncli: i32,                          // Depth of pushcli nesting.
     pub intena: bool,                       // Were interrupts enabled before pushcli?
-    pub proc: *const proc,                  // The process running on this cpu or null
+    pub proc: *mut proc,                    // The process running on this cpu or null
 }
 
 impl CPU {
@@ -25,13 +33,13 @@ impl CPU {
         CPU {
             id,
             apicid,
-            scheduler: core::ptr::null(),
+            scheduler: core::ptr::null_mut(),
             ts: mmu::taskstate::new(),
             gdt: [mmu::SegDesc::zero(); mmu::seg::NUM],
             started: false,
             ncli: 0,
             intena: false,
-            proc: core::ptr::null(),
+            proc: core::ptr::null_mut(),
         }
     }
     pub fn cpuid(&self) -> usize {
@@ -50,6 +58,7 @@ impl CPU {
 // at the "Switch stacks" comment. Switch doesn't save eip explicitly,
 // but it is on the stack and allocproc() manipulates it.
 #[derive(Debug)]
+#[repr(C)]
 pub struct context {
     edi: u32,
     esi: u32,
@@ -58,7 +67,8 @@ pub struct context {
     eip: u32,
 }
 
-enum procstate {
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum procstate {
     UNUSED,
     EMBRYO,
     SLEEPING,
@@ -69,28 +79,100 @@ enum procstate {
 
 // Per-process status
 pub struct proc {
-    sz: usize,                          // Size of process memory (bytes)
-    pgdir: *const vm::PageDirEntry,     // Page table
-    kstack: *const u8,                  // Bottom of kernel stack for this process
-    state: procstate,                   // Process state
-    pid: i32,                           // Process ID
-    parent: *const proc,                // Parent process
-    tf: *const x86::trapframe,          // Trap frame for current syscall
-    context: *const context,            // swtch() here to run process
-    chan: vaddr,                        // If non-zero, sleeping on chan
-    killed: bool,                       // If true, have been killed
-    ofile: [file::File; param::NOFILE], // Open files
-    cwd: *const file::Inode,            // Current directory
-    name: [u8; 16],                     // Process name (debugging)
+    pub sz: usize,                                     // Size of process memory (bytes)
+    pub guard: usize,                                  // Stack guard page below the heap
+    pub pgdir: Option<&'static mut vm::PageDir>,       // Page table
+    pub kstack: *mut u8,                               // Bottom of kernel stack for this process
+    pub state: procstate,                              // Process state
+    pub pid: i32,                                      // Process ID
+    parent: *const proc,                               // Parent process
+    pub tf: *mut x86::trapframe,                       // Trap frame for current syscall
+    context: *mut context,                             // swtch() here to run process
+    chan: vaddr,                                       // If non-zero, sleeping on chan
+    pub killed: bool,                                  // If true, have been killed
+    pub ofile: [Option<file::FileRef>; param::NOFILE], // Open files
+    pub cwd: Option<fs::Inode>,                        // Current directory
+    pub vma: [Option<mmap::Vma>; param::NVMA],         // mmap()ed regions
+    name: [u8; 16],                                    // Process name (debugging)
+}
+
+impl proc {
+    const fn new() -> Self {
+        const CLOSED: Option<file::FileRef> = None;
+        const UNMAPPED: Option<mmap::Vma> = None;
+        proc {
+            sz: 0,
+            guard: 0,
+            pgdir: None,
+            kstack: core::ptr::null_mut(),
+            state: procstate::UNUSED,
+            pid: 0,
+            parent: core::ptr::null(),
+            tf: core::ptr::null_mut(),
+            context: core::ptr::null_mut(),
+            chan: vaddr::new(),
+            killed: false,
+            ofile: [CLOSED; param::NOFILE],
+            cwd: None,
+            vma: [UNMAPPED; param::NVMA],
+            name: [0; 16],
+        }
+    }
+
+    // Process name, up to the first NUL (for debugging).
+    pub fn name(&self) -> &str {
+        let len = self.name.iter().position(|c| *c == 0).unwrap_or(16);
+        core::str::from_utf8(&self.name[..len]).unwrap_or("???")
+    }
+
+    // Set the process name, truncated to fit with a terminating NUL.
+    pub fn set_name(&mut self, name: &[u8]) {
+        let len = core::cmp::min(name.len(), self.name.len() - 1);
+        self.name[..len].copy_from_slice(&name[..len]);
+        utils::fill(&mut self.name[len..], 0);
+    }
+}
+
+struct ProcTable {
+    proc: [proc; param::NPROC],
+    nextpid: i32,
+}
+
+// The process table only holds raw pointers to memory owned by the processes.
+unsafe impl Send for ProcTable {}
+
+impl ProcTable {
+    const fn new() -> Self {
+        const INIT: proc = proc::new();
+        ProcTable {
+            proc: [INIT; param::NPROC],
+            nextpid: 1,
+        }
+    }
+}
+
+static ptable: SpinLock<ProcTable> = SpinLock::new("ptable", ProcTable::new());
+
+// The first user process.  Set once by userinit().
+static mut initproc: *const proc = core::ptr::null();
+
+global_asm!(include_str!("swtch.S"));
+global_asm!(include_str!("initcode.S"));
+extern "C" {
+    fn swtch(old: *mut *mut context, new: *mut context);
+    fn trapret();
+
+    static initcode_start: [u8; 0];
+    static initcode_end: [u8; 0];
 }
 
 pub fn pinit() {
-    // initilock
+    // ptable.lock is initialized statically.
 }
 
 // Must be called with interrupts disabled to avoid the caller being
 // rescheduled between reading lapicid and running through the loop.
-pub fn mycpu() -> &'static CPU {
+pub fn mycpu() -> &'static mut CPU {
     if x86::readflags().contains(EFlags::IF) {
         panic!("mycpu called with interrupts enabled\n");
     }
@@ -99,13 +181,561 @@ pub fn mycpu() -> &'static CPU {
     // APIC IDs are not guaranteed to be contiguous. Maybe we should have
     // a reverse map, or reserve a register to store &cpus[i].
     unsafe {
-        for c in mp::CPU_ARRAY.slice().iter() {
-            if let Some(c) = c {
-                if c.apicid == apicid {
-                    return c;
-                }
+        for i in 0..mp::CPU_ARRAY.len() {
+            let c = mp::CPU_ARRAY.borrow_mut(i);
+            if c.apicid == apicid {
+                return c;
             }
         }
     }
     panic!("unknown apicid");
 }
+
+// Disable interrupts so that we are not rescheduled
+// while reading proc from the cpu structure
+pub fn myproc() -> Option<&'static mut proc> {
+    pushcli();
+    let p = mycpu().proc;
+    popcli();
+    unsafe { p.as_mut() }
+}
+
+// Look in the process table for an UNUSED proc.
+// If found, change state to EMBRYO and initialize
+// state required to run in the kernel.
+// Otherwise return None.
+fn allocproc() -> Option<&'static mut proc> {
+    let p: *mut proc = {
+        let mut table = ptable.lock();
+        let idx = table
+            .proc
+            .iter()
+            .position(|p| p.state == procstate::UNUSED)?;
+        let pid = table.nextpid;
+        table.nextpid += 1;
+
+        let p = &mut table.proc[idx];
+        p.state = procstate::EMBRYO;
+        p.pid = pid;
+        p
+    };
+    // An EMBRYO slot is owned by the caller, so it can be set up
+    // without holding ptable.lock.
+    let p = unsafe { &mut *p };
+
+    // Allocate kernel stack.
+    match kalloc::kalloc() {
+        Some(page) => p.kstack = page.as_mut_ptr(),
+        None => {
+            p.state = procstate::UNUSED;
+            return None;
+        }
+    }
+    let mut sp = p.kstack as usize + param::KSTACKSIZE;
+
+    // Leave room for trap frame.
+    sp -= core::mem::size_of::<x86::trapframe>();
+    p.tf = sp as *mut x86::trapframe;
+
+    // Set up new context to start executing at forkret,
+    // which returns to trapret.
+    sp -= core::mem::size_of::<usize>();
+    unsafe {
+        *(sp as *mut usize) = trapret as usize;
+    }
+
+    sp -= core::mem::size_of::<context>();
+    p.context = sp as *mut context;
+    unsafe {
+        core::ptr::write(
+            p.context,
+            context {
+                edi: 0,
+                esi: 0,
+                ebx: 0,
+                ebp: 0,
+                eip: forkret as usize as u32,
+            },
+        );
+    }
+
+    Some(p)
+}
+
+// Free the kernel stack and page table of an EMBRYO or ZOMBIE
+// process and return its slot to the table.
+fn freeproc(p: &mut proc) {
+    kalloc::kfree(unsafe { &mut *(p.kstack as *mut mmu::Page) });
+    p.kstack = core::ptr::null_mut();
+    if let Some(pgdir) = p.pgdir.take() {
+        vm::freevm(pgdir);
+    }
+    p.sz = 0;
+    p.guard = 0;
+    p.pid = 0;
+    p.parent = core::ptr::null();
+    p.name[0] = 0;
+    p.killed = false;
+    p.state = procstate::UNUSED;
+}
+
+// Processes waiting in wait() sleep on their own proc address.
+fn proc_chan(p: *const proc) -> vaddr {
+    vaddr::from_ptr(p).unwrap()
+}
+
+// The image of initcode.S, which userinit() loads at address 0.
+fn initcode() -> &'static [u8] {
+    unsafe {
+        let size = initcode_end.as_ptr().offset_from(initcode_start.as_ptr()) as usize;
+        core::slice::from_raw_parts(initcode_start.as_ptr(), size)
+    }
+}
+
+// Set up first user process.
+pub fn userinit() {
+    let p = allocproc().expect("userinit: out of processes");
+    unsafe {
+        initproc = p as *const proc;
+    }
+
+    let pgdir = vm::setupkvm().expect("userinit: out of memory?");
+    vm::inituvm(pgdir, initcode());
+    p.pgdir = Some(pgdir);
+    p.sz = mmu::PGSIZE;
+
+    let tf = unsafe {
+        core::ptr::write_bytes(p.tf, 0, 1);
+        &mut *p.tf
+    };
+    {
+        use mmu::seg::*;
+        tf.cs = ((UCODE << 3) as u16) | DPL_USER as u16;
+        tf.ds = ((UDATA << 3) as u16) | DPL_USER as u16;
+        tf.es = tf.ds;
+        tf.ss = tf.ds;
+    }
+    tf.eflags = EFlags::IF.bits();
+    tf.esp = mmu::PGSIZE as u32;
+    tf.eip = 0; // beginning of initcode.S
+
+    p.set_name(b"initcode");
+    p.cwd = fs::namei(b"/");
+
+    // this assignment to p->state lets other cores
+    // run this process. the acquire forces the above
+    // writes to be visible, and the lock is also needed
+    // because the assignment might not be atomic.
+    let _table = ptable.lock();
+    p.state = procstate::RUNNABLE;
+}
+
+// Grow current process's memory by n bytes.
+// Growing only moves sz; the pages are allocated zeroed
+// on first touch by the page fault handler (see traps.rs).
+pub fn growproc(n: i32) -> Result<(), &'static str> {
+    let curproc = myproc().expect("growproc: no process");
+    let top = mmap::mmapbase(curproc);
+    let pgdir = curproc.pgdir.as_mut().expect("growproc: no pgdir");
+    let sz = curproc.sz;
+    let newsz = if n >= 0 {
+        // The heap must stay below the mmap()ed regions.
+        match sz.checked_add(n as usize) {
+            Some(newsz) if newsz <= top => newsz,
+            _ => return Err("growproc: too large"),
+        }
+    } else {
+        let newsz = sz
+            .checked_sub(n.wrapping_neg() as u32 as usize)
+            .ok_or("growproc: negative size")?;
+        vm::deallocuvm(pgdir, sz, newsz)
+    };
+    curproc.sz = newsz;
+    vm::switchuvm(curproc);
+    Ok(())
+}
+
+// Create a new process copying the current process as the parent.
+// Sets up stack to return as if from system call.
+pub fn fork() -> Result<i32, &'static str> {
+    let curproc = myproc().expect("fork: no process");
+
+    // Allocate process.
+    let np = allocproc().ok_or("fork: out of processes")?;
+
+    // Copy process state from proc.
+    match vm::copyuvm(curproc.pgdir.as_mut().unwrap(), curproc.sz) {
+        Some(pgdir) => np.pgdir = Some(pgdir),
+        None => {
+            freeproc(np);
+            return Err("fork: out of memory");
+        }
+    }
+    if let Err(e) = mmap::mmapfork(curproc, np) {
+        for v in np.vma.iter_mut() {
+            *v = None;
+        }
+        freeproc(np);
+        return Err(e);
+    }
+    np.sz = curproc.sz;
+    np.guard = curproc.guard;
+    np.parent = curproc as *const proc;
+    unsafe {
+        *np.tf = *curproc.tf;
+
+        // Clear %eax so that fork returns 0 in the child.
+        (*np.tf).eax = 0;
+    }
+
+    for (nf, f) in np.ofile.iter_mut().zip(curproc.ofile.iter()) {
+        *nf = f.clone();
+    }
+    np.cwd = curproc.cwd.clone();
+    np.name = curproc.name;
+
+    let pid = np.pid;
+
+    let _table = ptable.lock();
+    np.state = procstate::RUNNABLE;
+
+    Ok(pid)
+}
+
+// Exit the current process.  Does not return.
+// An exited process remains in the zombie state
+// until its parent calls wait() to find out it exited.
+pub fn exit() -> ! {
+    let curproc = myproc().expect("exit: no process");
+
+    if curproc as *const proc == unsafe { initproc } {
+        panic!("init exiting");
+    }
+
+    // Write back and unmap mmap()ed regions.
+    mmap::unmapall(curproc);
+
+    // Close all open files.
+    for f in curproc.ofile.iter_mut() {
+        *f = None;
+    }
+
+    log::begin_op();
+    curproc.cwd = None;
+    log::end_op();
+
+    let mut table = ptable.lock();
+
+    // Parent might be sleeping in wait().
+    wakeup1(&mut table, proc_chan(curproc.parent));
+
+    // Pass abandoned children to init.
+    let mut wakeinit = false;
+    for p in table.proc.iter_mut() {
+        if p.parent == curproc as *const proc {
+            p.parent = unsafe { initproc };
+            if p.state == procstate::ZOMBIE {
+                wakeinit = true;
+            }
+        }
+    }
+    if wakeinit {
+        wakeup1(&mut table, proc_chan(unsafe { initproc }));
+    }
+
+    // Jump into the scheduler, never to return.
+    curproc.state = procstate::ZOMBIE;
+    sched(&mut table);
+    panic!("zombie exit");
+}
+
+// Wait for a child process to exit and return its pid.
+// Return an error if this process has no children.
+pub fn wait() -> Result<i32, &'static str> {
+    let curproc = myproc().expect("wait: no process");
+
+    let mut table = ptable.lock();
+    loop {
+        // Scan through table looking for exited children.
+        let mut havekids = false;
+        for p in table.proc.iter_mut() {
+            if p.parent != curproc as *const proc {
+                continue;
+            }
+            havekids = true;
+            if p.state == procstate::ZOMBIE {
+                // Found one.
+                let pid = p.pid;
+                freeproc(p);
+                return Ok(pid);
+            }
+        }
+
+        // No point waiting if we don't have any children.
+        if !havekids {
+            return Err("wait: no children");
+        }
+        if curproc.killed {
+            return Err("wait: killed");
+        }
+
+        // Wait for children to exit.  (See wakeup1 call in exit.)
+        table = sleep1(proc_chan(curproc), table);
+    }
+}
+
+// Per-CPU process scheduler.
+// Each CPU calls scheduler() after setting itself up.
+// Scheduler never returns.  It loops, doing:
+//  - choose a process to run
+//  - swtch to start running that process
+//  - eventually that process transfers control
+//      via swtch back to the scheduler.
+pub fn scheduler() -> ! {
+    let c = mycpu();
+    c.proc = core::ptr::null_mut();
+
+    loop {
+        // Enable interrupts on this processor.
+        x86::sti();
+
+        // Loop over process table looking for process to run.
+        let mut table = ptable.lock();
+        for p in table.proc.iter_mut() {
+            if p.state != procstate::RUNNABLE {
+                continue;
+            }
+
+            // Switch to chosen process.  It is the process's job
+            // to release ptable.lock and then reacquire it
+            // before jumping back to us.
+            c.proc = p;
+            vm::switchuvm(p);
+            p.state = procstate::RUNNING;
+
+            unsafe {
+                swtch(&mut c.scheduler, p.context);
+            }
+            vm::switchkvm();
+
+            // Process is done running for now.
+            // It should have changed its p->state before coming back.
+            c.proc = core::ptr::null_mut();
+        }
+        drop(table);
+    }
+}
+
+// Enter scheduler.  Must hold only ptable.lock
+// and have changed proc->state. Saves and restores
+// intena because intena is a property of this
+// kernel thread, not this CPU. It should
+// be proc->intena and proc->ncli, but that would
+// break in the few places where a lock is held but
+// there's no process.
+fn sched(_ptable: &mut SpinLockGuard<ProcTable>) {
+    let p = myproc().expect("sched");
+
+    if !ptable.holding() {
+        panic!("sched ptable.lock");
+    }
+    if mycpu().ncli != 1 {
+        panic!("sched locks");
+    }
+    if p.state == procstate::RUNNING {
+        panic!("sched running");
+    }
+    if x86::readflags().contains(EFlags::IF) {
+        panic!("sched interruptible");
+    }
+    let intena = mycpu().intena;
+    unsafe {
+        swtch(&mut p.context, mycpu().scheduler);
+    }
+    mycpu().intena = intena;
+}
+
+// Give up the CPU for one scheduling round.
+pub fn yield_cpu() {
+    let mut table = ptable.lock();
+    myproc().expect("yield").state = procstate::RUNNABLE;
+    sched(&mut table);
+}
+
+// Set until the first process has initialized the file system.
+static FIRST: AtomicBool = AtomicBool::new(true);
+
+// A fork child's very first scheduling by scheduler()
+// will swtch here.  "Return" to user space.
+extern "C" fn forkret() {
+    // Still holding ptable.lock from scheduler.
+    unsafe {
+        ptable.force_release();
+    }
+
+    if FIRST.swap(false, Ordering::SeqCst) {
+        // Some initialization functions must be run in the context
+        // of a regular process (e.g., they call sleep), and thus cannot
+        // be run from main().
+        // Test kernels run their tests here instead.
+        #[cfg(test)]
+        super::test_main();
+        fs::iinit(param::ROOTDEV);
+        log::initlog(param::ROOTDEV);
+    }
+
+    // Return to "caller", actually trapret (see allocproc).
+}
+
+// Print a process listing to console.  For debugging.
+// Runs when user types ^P on console.
+// No lock to avoid wedging a stuck machine further.
+pub fn procdump() {
+    let table = unsafe { &*ptable.as_mut_ptr() };
+    for p in table.proc.iter() {
+        if p.state == procstate::UNUSED {
+            continue;
+        }
+        println!("{} {:?} {}", p.pid, p.state, p.name());
+    }
+}
+
+// Atomically release lock and sleep on chan.
+// Reacquires lock when awakened.
+// The lock must not be ptable.lock; use sleep1 for that.
+pub fn sleep<'a, T: ?Sized>(chan: vaddr, guard: SpinLockGuard<'a, T>) -> SpinLockGuard<'a, T> {
+    if myproc().is_none() {
+        panic!("sleep");
+    }
+    let lk = guard.spinlock();
+
+    // Must acquire ptable.lock in order to
+    // change p->state and then call sched.
+    // Once we hold ptable.lock, we can be
+    // guaranteed that we won't miss any wakeup
+    // (wakeup runs with ptable.lock locked),
+    // so it's okay to release lk.
+    let table = ptable.lock();
+    drop(guard);
+
+    let table = sleep1(chan, table);
+
+    // Reacquire original lock.
+    drop(table);
+    lk.lock()
+}
+
+// Sleep on chan with ptable.lock already held.
+fn sleep1<'a>(
+    chan: vaddr,
+    mut table: SpinLockGuard<'a, ProcTable>,
+) -> SpinLockGuard<'a, ProcTable> {
+    let p = myproc().expect("sleep");
+
+    // Go to sleep.
+    p.chan = chan;
+    p.state = procstate::SLEEPING;
+
+    sched(&mut table);
+
+    // Tidy up.
+    p.chan = vaddr::new();
+
+    table
+}
+
+// Wake up all processes sleeping on chan.
+// The ptable lock must be held.
+fn wakeup1(table: &mut ProcTable, chan: vaddr) {
+    for p in table.proc.iter_mut() {
+        if p.state == procstate::SLEEPING && p.chan == chan {
+            p.state = procstate::RUNNABLE;
+        }
+    }
+}
+
+// Wake up all processes sleeping on chan.
+pub fn wakeup(chan: vaddr) {
+    let mut table = ptable.lock();
+    wakeup1(&mut table, chan);
+}
+
+// Kill the process with the given pid.
+// Process won't exit until it returns
+// to user space (see trap in traps.rs).
+pub fn kill(pid: i32) -> Result<(), &'static str> {
+    let mut table = ptable.lock();
+    for p in table.proc.iter_mut() {
+        if p.state != procstate::UNUSED && p.pid == pid {
+            p.killed = true;
+            // Wake process from sleep if necessary.
+            if p.state == procstate::SLEEPING {
+                p.state = procstate::RUNNABLE;
+            }
+            return Ok(());
+        }
+    }
+    Err("kill: no such process")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::syscall::SYS_EXIT;
+    use crate::traps::T_SYSCALL;
+
+    fn fork_and_wait(n: usize) {
+        for _ in 0..n {
+            fork().expect("fork");
+        }
+        for _ in 0..n {
+            wait().expect("wait");
+        }
+    }
+
+    // Fork, exit and wait for many children, each of which writes
+    // to the shared page and so takes its own copy of it.
+    // Every page they used must be back on the free list afterwards.
+    #[test_case]
+    fn fork_exit_wait_frees_all_pages() {
+        // The children run a program on a page of their own,
+        // added to the first process for the test: it stores
+        // a byte to the end of the page and exits.
+        let p = myproc().unwrap();
+        let page = p.sz;
+        growproc(mmu::PGSIZE as i32).expect("growproc");
+        let data = page + mmu::PGSIZE - 1;
+        let pgdir = p.pgdir.as_mut().unwrap();
+        vm::prefault(pgdir, page, page + mmu::PGSIZE, true).expect("prefault");
+        let prog: [&[u8]; 5] = [
+            &[0xc6, 0x05], // movb $1, data
+            &(data as u32).to_le_bytes(),
+            &[1],
+            &[0xb8, SYS_EXIT as u8, 0, 0, 0], // movl $SYS_EXIT, %eax
+            &[0xcd, T_SYSCALL as u8],         // int $T_SYSCALL
+        ];
+        let mut va = page;
+        for bytes in prog.iter() {
+            vm::copyout(pgdir, va, bytes).unwrap();
+            va += bytes.len();
+        }
+        let tf = unsafe { &mut *p.tf };
+        let eip = tf.eip;
+        tf.eip = page as u32;
+
+        // The first fork may grow the kernel heap for good.
+        fork_and_wait(1);
+        let nfree = kalloc::freecount();
+        // Fork in batches; the process table is small.
+        for _ in 0..10 {
+            fork_and_wait(param::NPROC / 4);
+        }
+        // The children's writes went to their own copies.
+        let mem = vm::uva2ka(p.pgdir.as_ref().unwrap(), page).unwrap();
+        assert_eq!(unsafe { *mem.add(mmu::PGSIZE - 1) }, 0);
+        assert_eq!(kalloc::freecount(), nfree);
+
+        tf.eip = eip;
+        growproc(-(mmu::PGSIZE as i32)).expect("growproc");
+    }
+}
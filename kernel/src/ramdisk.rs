@@ -0,0 +1,75 @@
+// A RAM-backed block device.
+// Stands in for the IDE disk in test kernels, so the buffer cache,
+// log and file system can be tested without a disk image.
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+
+use super::bio::BlockDevice;
+use super::buf::{Buf, BufFlags};
+use super::fs::BSIZE;
+use super::spinlock::SpinLock;
+
+pub const NBLOCKS: usize = 256; // size of the disk in blocks
+
+struct Disk {
+    blocks: Vec<Box<[u8; BSIZE]>>, // grows on first use of a block
+    reads: usize,
+    writes: usize,
+}
+
+pub struct RamDisk {
+    disk: SpinLock<Disk>,
+}
+
+pub static RAMDISK: RamDisk = RamDisk {
+    disk: SpinLock::new(
+        "ramdisk",
+        Disk {
+            blocks: Vec::new(),
+            reads: 0,
+            writes: 0,
+        },
+    ),
+};
+
+impl Disk {
+    fn block(&mut self, blockno: u32) -> &mut [u8; BSIZE] {
+        let i = blockno as usize;
+        if i >= NBLOCKS {
+            panic!("ramdisk: block {} out of range", blockno);
+        }
+        while self.blocks.len() <= i {
+            self.blocks.push(Box::new([0; BSIZE]));
+        }
+        &mut self.blocks[i]
+    }
+}
+
+impl RamDisk {
+    // Contents of block blockno, bypassing the buffer cache.
+    pub fn read(&self, blockno: u32) -> [u8; BSIZE] {
+        *self.disk.lock().block(blockno)
+    }
+
+    // Number of blocks read and written through rw() so far.
+    pub fn stats(&self) -> (usize, usize) {
+        let d = self.disk.lock();
+        (d.reads, d.writes)
+    }
+}
+
+impl BlockDevice for RamDisk {
+    fn rw(&self, b: &mut Buf) {
+        let mut d = self.disk.lock();
+        if b.flags.contains(BufFlags::DIRTY) {
+            d.block(b.blockno).copy_from_slice(&b.data);
+            d.writes += 1;
+        } else {
+            b.data.copy_from_slice(d.block(b.blockno));
+            d.reads += 1;
+        }
+        b.flags.insert(BufFlags::VALID);
+        b.flags.remove(BufFlags::DIRTY);
+    }
+}
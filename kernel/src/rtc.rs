@@ -0,0 +1,161 @@
+// CMOS real-time clock.
+
+use super::lapic::{self, CMOS_PORT, CMOS_RETURN};
+use super::x86;
+
+const CMOS_STATA: u8 = 0x0a;
+const CMOS_STATB: u8 = 0x0b;
+const CMOS_UIP: u8 = 1 << 7; // RTC update in progress
+
+const STATB_24H: u8 = 1 << 1; // hours are 0-23, not 1-12 with a PM bit
+const STATB_BIN: u8 = 1 << 2; // values are binary, not BCD
+
+const HOUR_PM: u8 = 1 << 7; // PM bit of the hour in 12-hour mode
+
+const SECS: u8 = 0x00;
+const MINS: u8 = 0x02;
+const HOURS: u8 = 0x04;
+const DAY: u8 = 0x07;
+const MONTH: u8 = 0x08;
+const YEAR: u8 = 0x09;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct rtcdate {
+    pub second: u32,
+    pub minute: u32,
+    pub hour: u32,
+    pub day: u32,
+    pub month: u32,
+    pub year: u32,
+}
+
+// Wall clock at boot, in seconds since 1970-01-01 00:00:00 UTC,
+// and the lapic::nanotime() it was read at.
+// Set once by rtc_init on the boot CPU.
+static mut boot_epoch: u64 = 0;
+static mut boot_nanotime: u64 = 0;
+
+fn cmos_read(reg: u8) -> u8 {
+    x86::outb(CMOS_PORT, reg);
+    lapic::microdelay(200);
+    x86::inb(CMOS_RETURN)
+}
+
+// Raw register values, as the RTC reports them.
+fn fill_rtcdate() -> [u8; 6] {
+    [
+        cmos_read(SECS),
+        cmos_read(MINS),
+        cmos_read(HOURS),
+        cmos_read(DAY),
+        cmos_read(MONTH),
+        cmos_read(YEAR),
+    ]
+}
+
+fn bcd2bin(x: u8) -> u8 {
+    (x >> 4) * 10 + (x & 0xf)
+}
+
+// Read the current date from the RTC.
+pub fn cmostime() -> rtcdate {
+    let sb = cmos_read(CMOS_STATB);
+    let bcd = sb & STATB_BIN == 0;
+
+    // make sure CMOS doesn't modify time while we read it
+    let raw = loop {
+        while cmos_read(CMOS_STATA) & CMOS_UIP != 0 {
+            x86::nop();
+        }
+        let t1 = fill_rtcdate();
+        if cmos_read(CMOS_STATA) & CMOS_UIP == 0 && fill_rtcdate() == t1 {
+            break t1;
+        }
+    };
+
+    let conv = |x: u8| (if bcd { bcd2bin(x) } else { x }) as u32;
+
+    let mut hour = conv(raw[2] & !HOUR_PM);
+    if sb & STATB_24H == 0 {
+        // 12 AM is midnight, 12 PM is noon.
+        hour %= 12;
+        if raw[2] & HOUR_PM != 0 {
+            hour += 12;
+        }
+    }
+
+    rtcdate {
+        second: conv(raw[0]),
+        minute: conv(raw[1]),
+        hour,
+        day: conv(raw[3]),
+        month: conv(raw[4]),
+        year: conv(raw[5]) + 2000,
+    }
+}
+
+// Days since 1970-01-01 of a date in the proleptic Gregorian calendar.
+fn days_from_civil(year: u32, month: u32, day: u32) -> u64 {
+    let y = (if month <= 2 { year - 1 } else { year }) as u64;
+    let era = y / 400;
+    let yoe = y - era * 400; // [0, 399]
+    let mp = (month as u64 + 9) % 12; // March is 0
+    let doy = (153 * mp + 2) / 5 + day as u64 - 1; // [0, 365]
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy; // [0, 146096]
+    era * 146097 + doe - 719468
+}
+
+// Inverse of days_from_civil.
+fn civil_from_days(days: u64) -> (u32, u32, u32) {
+    let z = days + 719468;
+    let era = z / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year as u32, month as u32, day as u32)
+}
+
+pub fn date_to_epoch(d: &rtcdate) -> u64 {
+    days_from_civil(d.year, d.month, d.day) * 86400
+        + d.hour as u64 * 3600
+        + d.minute as u64 * 60
+        + d.second as u64
+}
+
+pub fn epoch_to_date(secs: u64) -> rtcdate {
+    let (year, month, day) = civil_from_days(secs / 86400);
+    let rem = secs % 86400;
+    rtcdate {
+        second: (rem % 60) as u32,
+        minute: (rem / 60 % 60) as u32,
+        hour: (rem / 3600) as u32,
+        day,
+        month,
+        year,
+    }
+}
+
+// Read the RTC once; afterwards the wall clock runs off the TSC.
+// Must be called after lapic_init has calibrated it.
+pub fn rtc_init() {
+    let d = cmostime();
+    unsafe {
+        boot_epoch = date_to_epoch(&d);
+        boot_nanotime = lapic::nanotime();
+    }
+    println!(
+        "rtc: {}-{:02}-{:02} {:02}:{:02}:{:02}",
+        d.year, d.month, d.day, d.hour, d.minute, d.second
+    );
+}
+
+// Current wall-clock time in seconds since the epoch.
+pub fn now() -> u64 {
+    let elapsed = lapic::nanotime().saturating_sub(unsafe { boot_nanotime });
+    unsafe { boot_epoch + elapsed / 1_000_000_000 }
+}
@@ -0,0 +1,100 @@
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+
+use super::proc;
+use super::spinlock::SpinLock;
+use super::utils::address::vaddr;
+
+struct LockState {
+    locked: bool, // Is the lock held?
+    pid: i32,     // Process holding lock
+}
+
+// Long-term locks for processes
+pub struct SleepLock<T: ?Sized> {
+    lk: SpinLock<LockState>, // spinlock protecting this sleep lock
+
+    // For debugging:
+    name: &'static str, // Name of lock.
+
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Sync for SleepLock<T> {}
+unsafe impl<T: ?Sized + Send> Send for SleepLock<T> {}
+
+// The lock is held as long as the guard is alive.
+pub struct SleepLockGuard<'a, T: ?Sized> {
+    lock: &'a SleepLock<T>,
+}
+
+impl<T> SleepLock<T> {
+    pub const fn new(name: &'static str, data: T) -> Self {
+        SleepLock {
+            lk: SpinLock::new(
+                "sleep lock",
+                LockState {
+                    locked: false,
+                    pid: 0,
+                },
+            ),
+            name,
+            data: UnsafeCell::new(data),
+        }
+    }
+}
+
+impl<T: ?Sized> SleepLock<T> {
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    // Processes sleeping on this lock wait on its address.
+    fn chan(&self) -> vaddr {
+        vaddr::from_ptr(self as *const Self as *const u8).unwrap()
+    }
+
+    // Acquire the lock.
+    // Sleeps (gives up the CPU) until the lock is acquired,
+    // so it must be called from a process context.
+    pub fn lock(&self) -> SleepLockGuard<T> {
+        let mut lk = self.lk.lock();
+        while lk.locked {
+            lk = proc::sleep(self.chan(), lk);
+        }
+        lk.locked = true;
+        lk.pid = proc::myproc().expect("acquiresleep").pid;
+        SleepLockGuard { lock: self }
+    }
+
+    // Release the lock and wake up waiting processes.
+    fn unlock(&self) {
+        let mut lk = self.lk.lock();
+        lk.locked = false;
+        lk.pid = 0;
+        proc::wakeup(self.chan());
+    }
+
+    // Check whether the current process is holding the lock.
+    pub fn holding(&self) -> bool {
+        let lk = self.lk.lock();
+        lk.locked && proc::myproc().map_or(false, |p| p.pid == lk.pid)
+    }
+}
+
+impl<'a, T: ?Sized> Deref for SleepLockGuard<'a, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+impl<'a, T: ?Sized> DerefMut for SleepLockGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+impl<'a, T: ?Sized> Drop for SleepLockGuard<'a, T> {
+    fn drop(&mut self) {
+        self.lock.unlock();
+    }
+}
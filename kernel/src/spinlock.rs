@@ -1,88 +1,189 @@
-use super::proc::CPU;
-use super::x86;
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicBool, Ordering};
 
-pub struct SpinLock {
-    pub locked: bool,
+use super::proc::{self, CPU};
+use super::vm;
+use super::x86::{self, EFlags};
 
-    // for debugging
-    name: Option<*const u8>,
-    cpu: CPU,
-    pcs: [u32; 10],
+// Mutual exclusion lock.
+pub struct SpinLock<T: ?Sized> {
+    locked: AtomicBool, // Is the lock held?
+
+    // For debugging:
+    name: &'static str,           // Name of lock.
+    cpu: UnsafeCell<*const CPU>,  // The cpu holding the lock.
+    pcs: UnsafeCell<[usize; 10]>, // The call stack that locked the lock.
+
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Sync for SpinLock<T> {}
+unsafe impl<T: ?Sized + Send> Send for SpinLock<T> {}
+
+// The lock is held as long as the guard is alive.
+pub struct SpinLockGuard<'a, T: ?Sized> {
+    lock: &'a SpinLock<T>,
 }
 
-impl SpinLock {
-    pub fn new() -> Self {
+impl<T> SpinLock<T> {
+    pub const fn new(name: &'static str, data: T) -> Self {
         SpinLock {
-            locked: false,
-            name: None,
-            cpu: CPU::new(),
-            pcs: [0; 10],
+            locked: AtomicBool::new(false),
+            name,
+            cpu: UnsafeCell::new(core::ptr::null()),
+            pcs: UnsafeCell::new([0; 10]),
+            data: UnsafeCell::new(data),
         }
     }
-    pub fn with_name(name: *const u8) -> Self {
-        SpinLock {
-            locked: false,
-            name: Some(name),
-            cpu: CPU::default(),
-            pcs: [0; 10],
-        }
+}
+
+impl<T: ?Sized> SpinLock<T> {
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    // Raw access to the protected data, bypassing the lock.
+    pub fn as_mut_ptr(&self) -> *mut T {
+        self.data.get()
     }
 
     // Acquire the lock.
     // Loops (spins) until the lock is acquired.
     // Holding a lock for a long time may cause
     // other CPUs to waste time spinning to acquire it.
-    pub fn acquire(&mut self) {
-        unimplemented!()
+    pub fn lock(&self) -> SpinLockGuard<T> {
+        self.acquire();
+        SpinLockGuard { lock: self }
+    }
+
+    fn acquire(&self) {
+        pushcli(); // disable interrupts to avoid deadlock.
+        if self.holding() {
+            panic!("acquire: {}", self.name);
+        }
+
+        // The xchg is atomic.
+        while self.locked.swap(true, Ordering::Acquire) {
+            x86::nop();
+        }
+
+        // Record info about lock acquisition for debugging.
+        unsafe {
+            *self.cpu.get() = proc::mycpu() as *const CPU;
+            getcallerpcs(&mut *self.pcs.get());
+        }
     }
 
     // Release the lock.
-    pub fn release(&mut self) {
-        unimplemented!()
+    fn release(&self) {
+        if !self.holding() {
+            panic!("release: {}", self.name);
+        }
+
+        unsafe {
+            (*self.pcs.get())[0] = 0;
+            *self.cpu.get() = core::ptr::null();
+        }
+
+        // Release the lock. The store is a release operation, so all the
+        // stores in the critical section are visible to other cores
+        // before the lock is released.
+        self.locked.store(false, Ordering::Release);
+
+        popcli();
     }
 
-    // Record the current call stack in pcs[] by following the %ebp chain.
-    pub fn getcallerpcs(&mut self, v: *const ()) {
-        unimplemented!()
+    // Release the lock without a guard.
+    // Only for the case where the lock was acquired by another context
+    // (e.g. ptable.lock held across swtch() into a new process).
+    pub unsafe fn force_release(&self) {
+        self.release();
     }
 
     // Check whether this cpu is holding the lock.
-    pub fn holding() -> bool {
-        unimplemented!()
+    pub fn holding(&self) -> bool {
+        pushcli();
+        let r = self.locked.load(Ordering::Relaxed)
+            && unsafe { *self.cpu.get() } == proc::mycpu() as *const CPU;
+        popcli();
+        r
+    }
+}
+
+impl<'a, T: ?Sized> SpinLockGuard<'a, T> {
+    // The lock this guard is holding.
+    pub fn spinlock(&self) -> &'a SpinLock<T> {
+        self.lock
+    }
+}
+
+impl<'a, T: ?Sized> Deref for SpinLockGuard<'a, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+impl<'a, T: ?Sized> DerefMut for SpinLockGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+impl<'a, T: ?Sized> Drop for SpinLockGuard<'a, T> {
+    fn drop(&mut self) {
+        self.lock.release();
+    }
+}
+
+// Record the current call stack in pcs[] by following the %ebp chain.
+pub fn getcallerpcs(pcs: &mut [usize; 10]) {
+    let mut ebp: *const usize;
+    unsafe {
+        asm!("movl %ebp, $0"
+                : "=r" (ebp)
+                ::: "volatile");
+    }
+
+    let mut i = 0;
+    while i < pcs.len() {
+        let addr = ebp as usize;
+        if addr == 0 || addr < vm::KERNBASE || addr == 0xffffffff {
+            break;
+        }
+        unsafe {
+            pcs[i] = *ebp.add(1); // saved %eip
+            ebp = *ebp as *const usize; // saved %ebp
+        }
+        i += 1;
+    }
+    for pc in pcs[i..].iter_mut() {
+        *pc = 0;
     }
 }
 
 // Pushcli/popcli are like cli/sti except that they are matched:
 // it takes two popcli to undo two pushcli.  Also, if interrupts
 // are off, then pushcli, popcli leaves them off.
-fn pushcli() {
-    // let eflags = x86::readflags();
-    // x86::cli();
-    unimplemented!()
-}
-fn popcli() {
-    unimplemented!()
+pub fn pushcli() {
+    let eflags = x86::readflags();
+    x86::cli();
+    let c = proc::mycpu();
+    if c.ncli == 0 {
+        c.intena = eflags.contains(EFlags::IF);
+    }
+    c.ncli += 1;
 }
 
-// void
-// pushcli(void)
-// {
-//   int eflags;
-
-//   eflags = readeflags();
-//   cli();
-//   if(mycpu()->ncli == 0)
-//     mycpu()->intena = eflags & FL_IF;
-//   mycpu()->ncli += 1;
-// }
-
-// void
-// popcli(void)
-// {
-//   if(readeflags()&FL_IF)
-//     panic("popcli - interruptible");
-//   if(--mycpu()->ncli < 0)
-//     panic("popcli");
-//   if(mycpu()->ncli == 0 && mycpu()->intena)
-//     sti();
-// }
+pub fn popcli() {
+    if x86::readflags().contains(EFlags::IF) {
+        panic!("popcli - interruptible");
+    }
+    let c = proc::mycpu();
+    c.ncli -= 1;
+    if c.ncli < 0 {
+        panic!("popcli");
+    }
+    if c.ncli == 0 && c.intena {
+        x86::sti();
+    }
+}
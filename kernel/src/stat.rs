@@ -0,0 +1,13 @@
+pub const T_DIR: i16 = 1; // Directory
+pub const T_FILE: i16 = 2; // File
+pub const T_DEV: i16 = 3; // Device
+
+#[derive(Debug, Default, Clone, Copy)]
+#[repr(C)]
+pub struct stat {
+    pub file_type: i16, // Type of file
+    pub dev: i32,       // File system's disk device
+    pub ino: u32,       // Inode number
+    pub nlink: i16,     // Number of links to file
+    pub size: u32,      // Size of file in bytes
+}
@@ -0,0 +1,176 @@
+use super::mmap;
+use super::mmu::PGSIZE;
+use super::proc;
+use super::sysfile;
+use super::sysproc;
+use super::utils::address::{uaddr, uaddr_raw};
+use super::vm;
+
+// System call numbers
+pub const SYS_FORK: usize = 1;
+pub const SYS_EXIT: usize = 2;
+pub const SYS_WAIT: usize = 3;
+pub const SYS_PIPE: usize = 4;
+pub const SYS_READ: usize = 5;
+pub const SYS_KILL: usize = 6;
+pub const SYS_EXEC: usize = 7;
+pub const SYS_FSTAT: usize = 8;
+pub const SYS_CHDIR: usize = 9;
+pub const SYS_DUP: usize = 10;
+pub const SYS_GETPID: usize = 11;
+pub const SYS_SBRK: usize = 12;
+pub const SYS_SLEEP: usize = 13;
+pub const SYS_UPTIME: usize = 14;
+pub const SYS_OPEN: usize = 15;
+pub const SYS_WRITE: usize = 16;
+pub const SYS_MKNOD: usize = 17;
+pub const SYS_UNLINK: usize = 18;
+pub const SYS_LINK: usize = 19;
+pub const SYS_MKDIR: usize = 20;
+pub const SYS_CLOSE: usize = 21;
+pub const SYS_DATE: usize = 22;
+pub const SYS_MMAP: usize = 23;
+pub const SYS_MUNMAP: usize = 24;
+pub const SYS_FDATE: usize = 25;
+
+// The value returned to user space in %eax.
+// Any error is reported to the user as -1.
+pub type SysResult = Result<usize, &'static str>;
+
+// User code makes a system call with INT T_SYSCALL.
+// System call number in %eax.
+// Arguments on the stack, from the user call to the C
+// library system call function. The saved user %esp points
+// to a saved program counter, and then the first argument.
+
+// Check that [addr, addr+len) lies within the current process's memory,
+// mmap()ed regions included.  The pages are faulted in here,
+// for writing if write is set, so that the kernel never faults on them
+// later: it may hold locks then, and can't fail if memory runs out.
+fn check_range(addr: uaddr, len: usize, write: bool) -> Result<(), &'static str> {
+    let p = proc::myproc().expect("check_range: no process");
+    match addr.as_raw().checked_add(len) {
+        Some(end) if end <= p.sz => {
+            vm::prefault(p.pgdir.as_mut().unwrap(), addr.as_raw(), end, write)
+        }
+        Some(end) => mmap::prefault(p, addr.as_raw(), end, write),
+        None => Err("user address out of range"),
+    }
+}
+
+// Fetch the int at addr from the current process.
+pub fn fetchint(addr: uaddr) -> Result<i32, &'static str> {
+    check_range(addr, core::mem::size_of::<i32>(), false)?;
+    Ok(unsafe { core::ptr::read_unaligned(addr.as_ptr::<i32>()) })
+}
+
+// Fetch the nul-terminated string at addr from the current process.
+// Doesn't actually copy the string - just returns the bytes
+// (without the nul) in the process's memory.
+pub fn fetchstr(addr: uaddr) -> Result<&'static [u8], &'static str> {
+    let sz = proc::myproc().expect("fetchstr: no process").sz;
+    let start = addr.as_raw();
+    let mut a = start;
+    loop {
+        // Check a page at a time, so that only
+        // the pages holding the string are faulted in.
+        let mut next = (a & !(PGSIZE - 1))
+            .checked_add(PGSIZE)
+            .ok_or("user address out of range")?;
+        if a < sz {
+            next = core::cmp::min(next, sz);
+        }
+        check_range(uaddr_raw(a), next - a, false)?;
+        let mem = unsafe { core::slice::from_raw_parts(uaddr_raw(a).as_ptr::<u8>(), next - a) };
+        if let Some(i) = mem.iter().position(|c| *c == 0) {
+            let len = a - start + i;
+            return Ok(unsafe { core::slice::from_raw_parts(addr.as_ptr::<u8>(), len) });
+        }
+        a = next;
+    }
+}
+
+// Fetch the nth 32-bit system call argument.
+pub fn argint(n: usize) -> Result<i32, &'static str> {
+    let p = proc::myproc().expect("argint: no process");
+    let esp = unsafe { (*p.tf).esp } as usize;
+    let addr = (4 + 4 * n)
+        .checked_add(esp)
+        .ok_or("user address out of range")?;
+    fetchint(uaddr_raw(addr))
+}
+
+// Fetch the nth word-sized system call argument as a user address.
+pub fn argaddr(n: usize) -> Result<uaddr, &'static str> {
+    Ok(uaddr_raw(argint(n)? as u32 as usize))
+}
+
+// Fetch the nth word-sized system call argument as a pointer
+// to a block of memory of size bytes.  Check that the pointer
+// lies within the process address space, writable by the kernel.
+pub fn argptr(n: usize, size: usize) -> Result<&'static mut [u8], &'static str> {
+    let addr = argaddr(n)?;
+    check_range(addr, size, true)?;
+    Ok(unsafe { core::slice::from_raw_parts_mut(addr.as_mut_ptr::<u8>(), size) })
+}
+
+// Like argptr, but for memory the kernel only reads,
+// which may be read-only to the process too.
+pub fn argptr_ro(n: usize, size: usize) -> Result<&'static [u8], &'static str> {
+    let addr = argaddr(n)?;
+    check_range(addr, size, false)?;
+    Ok(unsafe { core::slice::from_raw_parts(addr.as_ptr::<u8>(), size) })
+}
+
+// Fetch the nth word-sized system call argument as a string pointer.
+// Check that the pointer is valid and the string is nul-terminated.
+// (The string stays in user memory, and if it lies in a writable
+// MAP_SHARED mapping another process can change it meanwhile.
+// Its length is fixed by this check, so the kernel never reads
+// past it, but callers must not expect its bytes to stay the same.)
+pub fn argstr(n: usize) -> Result<&'static [u8], &'static str> {
+    fetchstr(argaddr(n)?)
+}
+
+pub fn syscall() {
+    let p = proc::myproc().expect("syscall: no process");
+    let num = unsafe { (*p.tf).eax } as usize;
+
+    let ret = match num {
+        SYS_FORK => sysproc::sys_fork(),
+        SYS_EXIT => sysproc::sys_exit(),
+        SYS_WAIT => sysproc::sys_wait(),
+        SYS_KILL => sysproc::sys_kill(),
+        SYS_EXEC => sysfile::sys_exec(),
+        SYS_PIPE => sysfile::sys_pipe(),
+        SYS_READ => sysfile::sys_read(),
+        SYS_FSTAT => sysfile::sys_fstat(),
+        SYS_CHDIR => sysfile::sys_chdir(),
+        SYS_DUP => sysfile::sys_dup(),
+        SYS_GETPID => sysproc::sys_getpid(),
+        SYS_SBRK => sysproc::sys_sbrk(),
+        SYS_SLEEP => sysproc::sys_sleep(),
+        SYS_UPTIME => sysproc::sys_uptime(),
+        SYS_OPEN => sysfile::sys_open(),
+        SYS_WRITE => sysfile::sys_write(),
+        SYS_MKNOD => sysfile::sys_mknod(),
+        SYS_UNLINK => sysfile::sys_unlink(),
+        SYS_LINK => sysfile::sys_link(),
+        SYS_MKDIR => sysfile::sys_mkdir(),
+        SYS_CLOSE => sysfile::sys_close(),
+        SYS_DATE => sysproc::sys_date(),
+        SYS_MMAP => sysfile::sys_mmap(),
+        SYS_MUNMAP => sysfile::sys_munmap(),
+        SYS_FDATE => sysfile::sys_fdate(),
+        _ => {
+            println!("{} {}: unknown sys call {}", p.pid, p.name(), num);
+            Err("unknown sys call")
+        }
+    };
+
+    let tf = unsafe { &mut *p.tf };
+    tf.eax = match ret {
+        Ok(v) => v as u32,
+        Err(_) => -1i32 as u32,
+    };
+}
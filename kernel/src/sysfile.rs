@@ -0,0 +1,350 @@
+//
+// File-system system calls.
+// Mostly argument checking, since we don't trust
+// user code, and calls into file.rs and fs.rs.
+//
+
+use super::exec;
+use super::fcntl::{MAP_ANONYMOUS, O_CREATE, O_RDONLY, O_RDWR, O_WRONLY};
+use super::file::{self, FileRef, FileType};
+use super::fs::{self, DIRENT_SIZE, DIRSIZ};
+use super::log;
+use super::mmap;
+use super::param::{MAXARG, NOFILE};
+use super::pipe;
+use super::proc;
+use super::rtc;
+use super::stat::{self, T_DEV, T_DIR, T_FILE};
+use super::syscall::{argaddr, argint, argptr, argptr_ro, argstr, fetchint, fetchstr, SysResult};
+use super::utils::address::uaddr_raw;
+use super::utils::common::bytes_from_ref;
+
+// Run f inside a file system transaction.
+// Inodes dropped by f are released before the transaction ends.
+fn transaction<T>(f: impl FnOnce() -> T) -> T {
+    log::begin_op();
+    let r = f();
+    log::end_op();
+    r
+}
+
+// Fetch the nth word-sized system call argument as a file descriptor
+// and return both the descriptor and the corresponding open file.
+fn argfd(n: usize) -> Result<(usize, &'static FileRef), &'static str> {
+    let fd = argint(n)?;
+    if fd < 0 || fd as usize >= NOFILE {
+        return Err("bad file descriptor");
+    }
+    let p = proc::myproc().unwrap();
+    match &p.ofile[fd as usize] {
+        Some(f) => Ok((fd as usize, f)),
+        None => Err("bad file descriptor"),
+    }
+}
+
+// Allocate a file descriptor for the given file.
+// Takes over file reference from caller on success;
+// drops it on failure.
+fn fdalloc(f: FileRef) -> Result<usize, &'static str> {
+    let p = proc::myproc().unwrap();
+    for (fd, slot) in p.ofile.iter_mut().enumerate() {
+        if slot.is_none() {
+            *slot = Some(f);
+            return Ok(fd);
+        }
+    }
+    Err("too many open files")
+}
+
+pub fn sys_dup() -> SysResult {
+    let (_, f) = argfd(0)?;
+    fdalloc(f.clone())
+}
+
+pub fn sys_read() -> SysResult {
+    let (_, f) = argfd(0)?;
+    let n = argint(2)?;
+    if n < 0 {
+        return Err("read: negative count");
+    }
+    f.fileread(argptr(1, n as usize)?)
+}
+
+pub fn sys_write() -> SysResult {
+    let (_, f) = argfd(0)?;
+    let n = argint(2)?;
+    if n < 0 {
+        return Err("write: negative count");
+    }
+    f.filewrite(argptr_ro(1, n as usize)?)
+}
+
+pub fn sys_close() -> SysResult {
+    let (fd, _) = argfd(0)?;
+    let f = proc::myproc().unwrap().ofile[fd].take();
+    drop(f);
+    Ok(0)
+}
+
+pub fn sys_fstat() -> SysResult {
+    let (_, f) = argfd(0)?;
+    let st = argptr(1, core::mem::size_of::<stat::stat>())?;
+    st.copy_from_slice(bytes_from_ref(&f.filestat()?));
+    Ok(0)
+}
+
+// Fill in the date and time the file open as fd was last modified.
+// Fails if that is unknown: the stamp is only kept while the
+// inode is cached, since the xv6 dinode has no room for it.
+pub fn sys_fdate() -> SysResult {
+    let (_, f) = argfd(0)?;
+    let r = argptr(1, core::mem::size_of::<rtc::rtcdate>())?;
+    let ip = f.inode().ok_or("fdate: not an inode")?;
+    let mtime = ip.ilock().mtime();
+    if mtime == 0 {
+        return Err("fdate: modification time unknown");
+    }
+    r.copy_from_slice(bytes_from_ref(&rtc::epoch_to_date(mtime)));
+    Ok(0)
+}
+
+// Create the path new as a link to the same inode as old.
+pub fn sys_link() -> SysResult {
+    let old = argstr(0)?;
+    let new = argstr(1)?;
+
+    transaction(|| {
+        let ip = fs::namei(old).ok_or("link: no such file")?;
+        {
+            let mut g = ip.ilock();
+            if g.file_type == T_DIR {
+                return Err("link: is a directory");
+            }
+            g.nlink += 1;
+            g.iupdate();
+        }
+
+        let mut name = [0; DIRSIZ];
+        let linked = match fs::nameiparent(new, &mut name) {
+            Some(dp) => {
+                let mut g = dp.ilock();
+                dp.dev == ip.dev && g.dirlink(&name, ip.inum).is_ok()
+            }
+            None => false,
+        };
+        if !linked {
+            let mut g = ip.ilock();
+            g.nlink -= 1;
+            g.iupdate();
+            return Err("link: cannot create link");
+        }
+        Ok(0)
+    })
+}
+
+// Is the directory dp empty except for "." and ".." ?
+fn isdirempty(dp: &mut fs::InodeGuard) -> bool {
+    let mut off = 2 * DIRENT_SIZE;
+    while off < dp.size {
+        if dp.readdirent(off).inum != 0 {
+            return false;
+        }
+        off += DIRENT_SIZE;
+    }
+    true
+}
+
+pub fn sys_unlink() -> SysResult {
+    let path = argstr(0)?;
+
+    transaction(|| {
+        let mut name = [0; DIRSIZ];
+        let dp = fs::nameiparent(path, &mut name).ok_or("unlink: no such file")?;
+        let mut dg = dp.ilock();
+
+        // Cannot unlink "." or "..".
+        if fs::namecmp(b".", &name) || fs::namecmp(b"..", &name) {
+            return Err("unlink: cannot unlink . or ..");
+        }
+
+        let (ip, off) = dg.dirlookup(&name).ok_or("unlink: no such file")?;
+        let mut ig = ip.ilock();
+
+        if ig.nlink < 1 {
+            panic!("unlink: nlink < 1");
+        }
+        if ig.file_type == T_DIR && !isdirempty(&mut ig) {
+            return Err("unlink: directory not empty");
+        }
+
+        let de = fs::dirent::default();
+        match dg.writei(bytes_from_ref(&de), off) {
+            Ok(n) if n == DIRENT_SIZE => {}
+            _ => panic!("unlink: writei"),
+        }
+        if ig.file_type == T_DIR {
+            dg.nlink -= 1;
+            dg.iupdate();
+        }
+        drop(dg);
+
+        ig.nlink -= 1;
+        ig.iupdate();
+        Ok(0)
+    })
+}
+
+// Create an inode named by path, or return the existing one
+// if path names a plain file and a plain file was asked for.
+// Must be called inside a transaction.
+fn create(path: &[u8], file_type: i16, major: i16, minor: i16) -> Option<fs::Inode> {
+    let mut name = [0; DIRSIZ];
+    let dp = fs::nameiparent(path, &mut name)?;
+    let mut dg = dp.ilock();
+
+    if let Some((ip, _)) = dg.dirlookup(&name) {
+        drop(dg);
+        let is_file = ip.ilock().file_type == T_FILE;
+        if file_type == T_FILE && is_file {
+            return Some(ip);
+        }
+        return None;
+    }
+
+    let ip = fs::ialloc(dp.dev, file_type);
+    {
+        let mut g = ip.ilock();
+        g.major = major;
+        g.minor = minor;
+        g.nlink = 1;
+        g.iupdate();
+
+        if file_type == T_DIR {
+            // Create . and .. entries.
+            dg.nlink += 1; // for ".."
+            dg.iupdate();
+            // No nlink++ for ".": avoid cyclic ref count.
+            if g.dirlink(b".", ip.inum).is_err() || g.dirlink(b"..", dp.inum).is_err() {
+                panic!("create dots");
+            }
+        }
+    }
+
+    if dg.dirlink(&name, ip.inum).is_err() {
+        panic!("create: dirlink");
+    }
+
+    Some(ip)
+}
+
+pub fn sys_open() -> SysResult {
+    let path = argstr(0)?;
+    let omode = argint(1)?;
+
+    let f = transaction(|| {
+        let ip = if omode & O_CREATE != 0 {
+            create(path, T_FILE, 0, 0)?
+        } else {
+            let ip = fs::namei(path)?;
+            if ip.ilock().file_type == T_DIR && omode != O_RDONLY {
+                // directories can only be opened read-only
+                return None;
+            }
+            ip
+        };
+        let readable = omode & O_WRONLY == 0;
+        let writable = omode & O_WRONLY != 0 || omode & O_RDWR != 0;
+        file::filealloc(FileType::FD_INODE(ip), readable, writable)
+    })
+    .ok_or("open: failed")?;
+
+    fdalloc(f)
+}
+
+pub fn sys_mkdir() -> SysResult {
+    let path = argstr(0)?;
+    transaction(|| create(path, T_DIR, 0, 0).map(|_| 0)).ok_or("mkdir: failed")
+}
+
+pub fn sys_mknod() -> SysResult {
+    let path = argstr(0)?;
+    let major = argint(1)? as i16;
+    let minor = argint(2)? as i16;
+    transaction(|| create(path, T_DEV, major, minor).map(|_| 0)).ok_or("mknod: failed")
+}
+
+pub fn sys_chdir() -> SysResult {
+    let path = argstr(0)?;
+    let p = proc::myproc().unwrap();
+
+    transaction(|| {
+        let ip = fs::namei(path).ok_or("chdir: no such directory")?;
+        if ip.ilock().file_type != T_DIR {
+            return Err("chdir: not a directory");
+        }
+        p.cwd = Some(ip);
+        Ok(0)
+    })
+}
+
+pub fn sys_exec() -> SysResult {
+    let path = argstr(0)?;
+    let uargv = argaddr(1)?.as_raw();
+    let mut argv: [&[u8]; MAXARG] = [&[]; MAXARG];
+    let mut argc = 0;
+    loop {
+        if argc >= MAXARG {
+            return Err("exec: too many arguments");
+        }
+        let addr = uargv.checked_add(4 * argc).ok_or("exec: bad argv")?;
+        let uarg = fetchint(uaddr_raw(addr))? as u32 as usize;
+        if uarg == 0 {
+            break;
+        }
+        argv[argc] = fetchstr(uaddr_raw(uarg))?;
+        argc += 1;
+    }
+    exec::exec(path, &argv[..argc])
+}
+
+pub fn sys_mmap() -> SysResult {
+    // The address hint in argument 0 is ignored.
+    let len = argint(1)? as u32 as usize;
+    let prot = argint(2)?;
+    let flags = argint(3)?;
+    let off = argint(5)?;
+    if off < 0 {
+        return Err("mmap: bad offset");
+    }
+    let file = if flags & MAP_ANONYMOUS != 0 {
+        None
+    } else {
+        Some(argfd(4)?.1.clone())
+    };
+    mmap::mmap(len, prot, flags, file, off as usize)
+}
+
+pub fn sys_munmap() -> SysResult {
+    let addr = argaddr(0)?.as_raw();
+    let len = argint(1)? as u32 as usize;
+    mmap::munmap(addr, len).map(|_| 0)
+}
+
+pub fn sys_pipe() -> SysResult {
+    let fd = argptr(0, 2 * core::mem::size_of::<i32>())?;
+    let (rf, wf) = pipe::pipealloc().ok_or("pipe: out of memory")?;
+    let fd0 = fdalloc(rf)?;
+    let fd1 = match fdalloc(wf) {
+        Ok(fd1) => fd1,
+        Err(e) => {
+            proc::myproc().unwrap().ofile[fd0] = None;
+            return Err(e);
+        }
+    };
+    unsafe {
+        let fd = fd.as_mut_ptr() as *mut i32;
+        core::ptr::write_unaligned(fd, fd0 as i32);
+        core::ptr::write_unaligned(fd.add(1), fd1 as i32);
+    }
+    Ok(0)
+}
@@ -0,0 +1,59 @@
+use super::proc;
+use super::rtc;
+use super::syscall::{argint, argptr, SysResult};
+use super::traps;
+use super::utils::common::bytes_from_ref;
+
+pub fn sys_fork() -> SysResult {
+    proc::fork().map(|pid| pid as usize)
+}
+
+pub fn sys_exit() -> SysResult {
+    proc::exit()
+}
+
+pub fn sys_wait() -> SysResult {
+    proc::wait().map(|pid| pid as usize)
+}
+
+pub fn sys_kill() -> SysResult {
+    let pid = argint(0)?;
+    proc::kill(pid).map(|_| 0)
+}
+
+pub fn sys_getpid() -> SysResult {
+    Ok(proc::myproc().unwrap().pid as usize)
+}
+
+pub fn sys_sleep() -> SysResult {
+    let n = argint(0)? as u32;
+    let mut t = traps::ticks.lock();
+    let ticks0 = *t;
+    while t.wrapping_sub(ticks0) < n {
+        if proc::myproc().unwrap().killed {
+            return Err("sleep: killed");
+        }
+        t = proc::sleep(traps::ticks_chan(), t);
+    }
+    Ok(0)
+}
+
+pub fn sys_sbrk() -> SysResult {
+    let n = argint(0)?;
+    let addr = proc::myproc().unwrap().sz;
+    proc::growproc(n)?;
+    Ok(addr)
+}
+
+// return how many clock tick interrupts have occurred
+// since start.
+pub fn sys_uptime() -> SysResult {
+    Ok(*traps::ticks.lock() as usize)
+}
+
+// Fill in the current wall-clock date and time.
+pub fn sys_date() -> SysResult {
+    let r = argptr(0, core::mem::size_of::<rtc::rtcdate>())?;
+    r.copy_from_slice(bytes_from_ref(&rtc::epoch_to_date(rtc::now())));
+    Ok(0)
+}
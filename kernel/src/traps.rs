@@ -1,6 +1,15 @@
+use super::ide;
+use super::kbd;
+use super::lapic;
+use super::mmap;
 use super::mmu;
-
-use spin::Mutex;
+use super::proc::{self, procstate};
+use super::spinlock::SpinLock;
+use super::syscall;
+use super::uart;
+use super::utils::address::vaddr;
+use super::vm;
+use super::x86;
 
 // x86 trap and interrupt constants.
 
@@ -45,9 +54,13 @@ extern "C" {
     static vectors: [u32; 256];
 }
 global_asm!(include_str!("vectors.S"));
+global_asm!(include_str!("trapasm.S"));
+
+pub static ticks: SpinLock<u32> = SpinLock::new("time", 0);
 
-lazy_static! {
-    static ref ticks: Mutex<u32> = Mutex::new(0);
+// Processes waiting for the clock sleep on the address of ticks.
+pub fn ticks_chan() -> vaddr {
+    vaddr::from_ptr(&ticks as *const SpinLock<u32>).unwrap()
 }
 
 pub fn tvinit() {
@@ -67,3 +80,162 @@ pub fn tvinit() {
         );
     }
 }
+
+pub fn idtinit() {
+    unsafe {
+        x86::lidt(idt.as_ptr(), core::mem::size_of_val(&idt) as u16);
+    }
+}
+
+// Page fault error code bits
+const FEC_P: u32 = 0x1; // fault on a present page (protection violation)
+const FEC_WR: u32 = 0x2; // fault caused by a write
+
+// Try to resolve a page fault at a user address of the current process,
+// whether it came from user code or from the kernel accessing user memory.
+// Handles the first touch of a lazily allocated heap or
+// mmap()ed page and writes to copy-on-write pages.
+fn pgfault(tf: &x86::trapframe) -> Result<(), &'static str> {
+    let p = proc::myproc().ok_or("no process")?;
+    let va = x86::rcr2();
+    if p.pgdir.is_none() {
+        return Err("no page table");
+    }
+    if tf.err & FEC_P == 0 {
+        if va < p.sz && va >= p.guard + mmu::PGSIZE {
+            // Heap pages between the stack and sz are allocated on demand.
+            vm::lazyalloc(p.pgdir.as_mut().unwrap(), va)
+        } else {
+            // So are the pages of mmap()ed regions.
+            mmap::mmapfault(p, va, tf.err & FEC_WR != 0)
+        }
+    } else if tf.err & FEC_WR != 0 {
+        vm::cowfault(p.pgdir.as_mut().unwrap(), va).map_err(|_| "write to read-only page")
+    } else {
+        Err("protection fault")
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn trap(tf: &mut x86::trapframe) {
+    if tf.trapno == T_SYSCALL as u32 {
+        let p = proc::myproc().expect("syscall: no process");
+        if p.killed {
+            proc::exit();
+        }
+        p.tf = tf;
+        syscall::syscall();
+        if p.killed {
+            proc::exit();
+        }
+        return;
+    }
+
+    match tf.trapno {
+        n if n == T_IRQ0 + IRQ_TIMER => {
+            if proc::mycpu().cpuid() == 0 {
+                let mut t = ticks.lock();
+                *t += 1;
+                proc::wakeup(ticks_chan());
+            }
+            lapic::lapiceoi();
+        }
+        n if n == T_IRQ0 + IRQ_IDE => {
+            ide::ideintr();
+            lapic::lapiceoi();
+        }
+        n if n == T_IRQ0 + IRQ_IDE + 1 => {
+            // Bochs generates spurious IDE1 interrupts.
+        }
+        n if n == T_IRQ0 + IRQ_KBD => {
+            kbd::kbdintr();
+            lapic::lapiceoi();
+        }
+        n if n == T_IRQ0 + IRQ_COM1 => {
+            uart::uartintr();
+            lapic::lapiceoi();
+        }
+        n if n == T_PGFLT => {
+            // On success, return to retry the faulting instruction.
+            if let Err(e) = pgfault(tf) {
+                match proc::myproc() {
+                    Some(ref mut p) if tf.cs & 3 != 0 => {
+                        println!(
+                            "pid {} {}: page fault: {} addr 0x{:x} eip 0x{:x}--kill proc",
+                            p.pid,
+                            p.name(),
+                            e,
+                            x86::rcr2(),
+                            tf.eip
+                        );
+                        p.killed = true;
+                    }
+                    _ => {
+                        panic!(
+                            "page fault in kernel: {} addr 0x{:x} eip 0x{:x}",
+                            e,
+                            x86::rcr2(),
+                            tf.eip
+                        );
+                    }
+                }
+            }
+        }
+        n if n == T_IRQ0 + 7 || n == T_IRQ0 + IRQ_SPURIOUS => {
+            println!(
+                "cpu{}: spurious interrupt at {:x}:{:x}",
+                proc::mycpu().cpuid(),
+                tf.cs,
+                tf.eip
+            );
+            lapic::lapiceoi();
+        }
+        _ => match proc::myproc() {
+            Some(ref mut p) if tf.cs & 3 != 0 => {
+                // In user space, assume process misbehaved.
+                println!(
+                    "pid {} {}: trap {} err {} on cpu {} eip 0x{:x} addr 0x{:x}--kill proc",
+                    p.pid,
+                    p.name(),
+                    tf.trapno,
+                    tf.err,
+                    proc::mycpu().cpuid(),
+                    tf.eip,
+                    x86::rcr2()
+                );
+                p.killed = true;
+            }
+            _ => {
+                // In kernel, it must be our mistake.
+                panic!(
+                    "unexpected trap {} from cpu {} eip {:x} (cr2=0x{:x})",
+                    tf.trapno,
+                    proc::mycpu().cpuid(),
+                    tf.eip,
+                    x86::rcr2()
+                );
+            }
+        },
+    }
+
+    let user_mode = tf.cs & 3 == mmu::seg::DPL_USER as u16;
+    if let Some(p) = proc::myproc() {
+        // Force process exit if it has been killed and is in user space.
+        // (If it is still executing in the kernel, let it keep running
+        // until it gets to the regular system call return.)
+        if p.killed && user_mode {
+            proc::exit();
+        }
+
+        // Force process to give up CPU on clock tick.
+        // If interrupts were on while locks held, would need to check nlock.
+        if p.state == procstate::RUNNING && tf.trapno == T_IRQ0 + IRQ_TIMER {
+            proc::yield_cpu();
+        }
+
+        // Check if the process has been killed since we yielded
+        if p.killed && user_mode {
+            proc::exit();
+        }
+    }
+}
@@ -1,39 +1,136 @@
+// Intel 8250 serial port (UART).
+
+use super::console;
 use super::ioapic;
-use super::lapic;
+use super::spinlock::SpinLock;
 use super::traps;
 use super::x86;
 
 const COM1: u16 = 0x3f8;
 
+// Register offsets from the base port.
+const RBR: u16 = 0; // receive buffer (read)
+const THR: u16 = 0; // transmit holding (write)
+const DLL: u16 = 0; // divisor latch low (DLAB=1)
+const IER: u16 = 1; // interrupt enable
+const DLM: u16 = 1; // divisor latch high (DLAB=1)
+const IIR: u16 = 2; // interrupt identification (read)
+const FCR: u16 = 2; // FIFO control (write)
+const LCR: u16 = 3; // line control
+const MCR: u16 = 4; // modem control
+const LSR: u16 = 5; // line status
+
+const IER_RDI: u8 = 0x01; // receive data available
+const IER_THRI: u8 = 0x02; // transmit holding register empty
+
+// Enable and clear both FIFOs, interrupt at 14 received bytes.
+const FCR_ENABLE_TRIGGER14: u8 = 0xC7;
+
+const LCR_DLAB: u8 = 0x80; // divisor latch access
+const LCR_8N1: u8 = 0x03; // 8 data bits, 1 stop bit, parity off
+
+const MCR_DTR_RTS_OUT2: u8 = 0x0B; // OUT2 gates the IRQ line on PCs
+
+const LSR_DR: u8 = 0x01; // data ready
+const LSR_THRE: u8 = 0x20; // transmit holding register empty
+
+const UART_CLOCK: u32 = 115200; // input clock / 16
+const FIFO_SIZE: usize = 16;
+const TX_BUF: usize = 128;
+
+struct TxRing {
+    buf: [u8; TX_BUF],
+    r: usize, // read index
+    w: usize, // write index
+}
+
+static uart_tx: SpinLock<TxRing> = SpinLock::new(
+    "uart",
+    TxRing {
+        buf: [0; TX_BUF],
+        r: 0,
+        w: 0,
+    },
+);
+
 static mut uart: bool = false; // is there a uart?
 
+// Move bytes from the ring into the transmit FIFO,
+// if the UART is ready for them.
+fn uartstart(tx: &mut TxRing) {
+    if x86::inb(COM1 + LSR) & LSR_THRE == 0 {
+        // The transmit FIFO is still busy;
+        // the THRE interrupt will call back.
+        return;
+    }
+    let mut n = 0;
+    while n < FIFO_SIZE && tx.r != tx.w {
+        x86::outb(COM1 + THR, tx.buf[tx.r % TX_BUF]);
+        tx.r = tx.r.wrapping_add(1);
+        n += 1;
+    }
+}
+
+// Queue c for transmission.  Only spins when the ring is full.
 pub fn putc(c: u8) {
     if unsafe { !uart } {
         return;
     }
-    for _ in 0..128 {
-        if x86::inb(COM1 + 5) & 0x20 > 0 {
-            break;
-        }
-        lapic::microdelay(10);
+    let mut tx = uart_tx.lock();
+    while tx.w.wrapping_sub(tx.r) == TX_BUF {
+        uartstart(&mut tx);
+    }
+    let w = tx.w;
+    tx.buf[w % TX_BUF] = c;
+    tx.w = w.wrapping_add(1);
+    uartstart(&mut tx);
+}
+
+// Write c straight to the UART, bypassing the ring, so that
+// test output gets out before QEMU exits, even from a panic.
+#[cfg(test)]
+pub fn putc_sync(c: u8) {
+    while x86::inb(COM1 + LSR) & LSR_THRE == 0 {
+        x86::nop();
+    }
+    x86::outb(COM1 + THR, c);
+}
+
+pub fn uartgetc() -> Option<u8> {
+    if unsafe { !uart } {
+        return None;
+    }
+    if x86::inb(COM1 + LSR) & LSR_DR == 0 {
+        return None;
     }
-    x86::outb(COM1 + 0, c);
+    Some(x86::inb(COM1 + RBR))
 }
 
-pub fn uart_init() {
-    // Turn off the FIFO
-    x86::outb(COM1 + 2, 0);
+pub fn uartintr() {
+    x86::inb(COM1 + IIR); // acknowledge
+    console::consoleintr(uartgetc);
+    uartstart(&mut uart_tx.lock());
+}
+
+pub fn uart_init(baud: u32) {
+    if baud == 0 || baud > UART_CLOCK {
+        panic!("uart_init: bad baud rate {}", baud);
+    }
+    let divisor = UART_CLOCK / baud;
+
+    // Turn on the FIFO
+    x86::outb(COM1 + FCR, FCR_ENABLE_TRIGGER14);
 
-    // 9600 baud, 8 data bits, 1 stop bit, parity off.
-    x86::outb(COM1 + 3, 0x80); // Unlock divisor
-    x86::outb(COM1 + 0, (115200 / 9600) as u8); // ?
-    x86::outb(COM1 + 1, 0);
-    x86::outb(COM1 + 3, 0x03); // Lock divisor, 8 data bits.
-    x86::outb(COM1 + 4, 0);
-    x86::outb(COM1 + 1, 0x01); // Enable receive interrupts.
+    // 8 data bits, 1 stop bit, parity off.
+    x86::outb(COM1 + LCR, LCR_DLAB); // Unlock divisor
+    x86::outb(COM1 + DLL, trunc8!(divisor));
+    x86::outb(COM1 + DLM, trunc8!(divisor >> 8));
+    x86::outb(COM1 + LCR, LCR_8N1); // Lock divisor, 8 data bits.
+    x86::outb(COM1 + MCR, MCR_DTR_RTS_OUT2);
+    x86::outb(COM1 + IER, IER_RDI | IER_THRI); // Enable receive and transmit interrupts.
 
     // If status is 0xFF, no serial port.
-    if x86::inb(COM1 + 5) == 0xFF {
+    if x86::inb(COM1 + LSR) == 0xFF {
         return;
     }
     unsafe {
@@ -42,8 +139,8 @@ pub fn uart_init() {
 
     // Acknowledge pre-existing interrupt conditions;
     // enable interrupts.
-    x86::inb(COM1 + 2);
-    x86::inb(COM1 + 0);
+    x86::inb(COM1 + IIR);
+    x86::inb(COM1 + RBR);
     ioapic::ioapic_enable(traps::IRQ_COM1, 0);
 
     // Announce that we're here.
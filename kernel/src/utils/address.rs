@@ -7,6 +7,9 @@ use core::num::Wrapping;
 pub struct Virtual;
 #[derive(Debug, Copy, Clone, PartialEq, PartialOrd)]
 pub struct Physical;
+// an address in the user part of a process's address space
+#[derive(Debug, Copy, Clone, PartialEq, PartialOrd)]
+pub struct User;
 
 #[derive(Debug, Copy, Clone, PartialEq, PartialOrd)]
 pub struct FreeAligned;
@@ -55,7 +58,7 @@ pub struct Address<T, A: Align> {
 }
 
 impl<T, A: Align> Address<T, A> {
-    pub fn new() -> Self {
+    pub const fn new() -> Self {
         Address {
             addr: 0,
             _phantom: PhantomData,
@@ -204,6 +207,8 @@ pub type paddr = Address<Physical, FreeAligned>;
 pub type vaddr_pg = Address<Virtual, PageAligned>;
 pub type paddr_pg = Address<Physical, PageAligned>;
 
+pub type uaddr = Address<User, FreeAligned>;
+
 #[inline]
 pub fn v2p<A: Align>(v: Address<Virtual, A>) -> Address<Physical, A> {
     Into::<Option<Address<Physical, A>>>::into(v).unwrap()
@@ -229,6 +234,10 @@ pub fn vaddr_raw(a: usize) -> vaddr {
 pub fn paddr_raw(a: usize) -> paddr {
     paddr::from_raw(a).unwrap()
 }
+#[inline]
+pub fn uaddr_raw(a: usize) -> uaddr {
+    uaddr::from_raw(a).unwrap()
+}
 
 // into FreeAligned is always successful
 impl<T> From<Address<T, PageAligned>> for Address<T, FreeAligned> {
@@ -8,8 +8,11 @@ pub struct Ptr<T> {
 }
 
 impl<T> Ptr<T> {
-    pub fn null() -> Self {
-        Self::from(vaddr::null())
+    pub const fn null() -> Self {
+        Ptr {
+            addr: vaddr::new(),
+            _phantom: PhantomData,
+        }
     }
     pub fn is_null(&self) -> bool {
         self.addr.is_null()
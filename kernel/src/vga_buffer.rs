@@ -103,6 +103,25 @@ impl Writer {
         }
     }
 
+    // Erase the character before the cursor.
+    pub fn backspace(&mut self) {
+        if self.column_position > 0 {
+            self.column_position -= 1;
+        } else if self.row_position > 0 {
+            self.row_position -= 1;
+            self.column_position = WIDTH - 1;
+        } else {
+            return;
+        }
+        let blank = ScreenCell {
+            ascii: b' ',
+            color: self.color,
+        };
+        self.buffer
+            .write(self.row_position, self.column_position, blank);
+        self.update_cursor();
+    }
+
     pub fn clear_screen(&mut self) {
         for r in 0..HEIGHT {
             self.clear_row(r);
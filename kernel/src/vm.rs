@@ -1,9 +1,13 @@
 use core::num::Wrapping;
+use core::ops::{Deref, DerefMut};
 
+use super::fs::InodeGuard;
 use super::kalloc;
 use super::mmu;
 use super::mp;
+use super::param;
 use super::proc;
+use super::spinlock::{popcli, pushcli};
 use super::utils;
 use super::utils::address::{
     p2v, p2v_raw, paddr, paddr_pg, paddr_raw, v2p, v2p_raw, vaddr, vaddr_pg, vaddr_raw,
@@ -14,10 +18,26 @@ use super::x86;
 pub type PageDirEntry = u32;
 pub type PageTableEntry = u32;
 
+// A page directory.  Always occupies a whole page from kalloc.
+#[repr(transparent)]
+pub struct PageDir([PageDirEntry; mmu::NPDENTRIES]);
+
+impl Deref for PageDir {
+    type Target = [PageDirEntry; mmu::NPDENTRIES];
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+impl DerefMut for PageDir {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
 const EXTMEM: usize = 0x100000; // Start of extended memory
-const PHYSTOP: usize = 0xE000000; // Top physical memory
+pub const PHYSTOP: usize = 0xE000000; // Top physical memory
 const DEVSPACE: usize = 0xFE000000; // Other devices are at high addresses
-const KERNBASE: usize = 0x80000000; // First kernel virtual address
+pub const KERNBASE: usize = 0x80000000; // First kernel virtual address
 const KERNLINK: usize = KERNBASE + EXTMEM; // Address where kernel is linked
 
 extern "C" {
@@ -25,7 +45,7 @@ extern "C" {
 }
 
 lazy_static! {
-    static ref kpgdir: Option<&'static [PageDirEntry; mmu::NPDENTRIES]> = setupkvm();
+    static ref kpgdir: Option<&'static PageDir> = setupkvm().map(|pgdir| &*pgdir);
 }
 
 //------------------------------------------------------------------------------
@@ -58,11 +78,7 @@ pub fn seg_init() {
 // Return the address of the PTE in page table pgdir
 // that corresponds to virtual address va.  If alloc!=0,
 // create any required page table pages.
-fn walkpgdir(
-    pgdir: &mut [PageDirEntry; mmu::NPDENTRIES],
-    va: vaddr_pg,
-    alloc: bool,
-) -> Option<&mut PageTableEntry> {
+pub fn walkpgdir(pgdir: &mut PageDir, va: vaddr_pg, alloc: bool) -> Option<&mut PageTableEntry> {
     let pde = &mut pgdir[mmu::pdx(va)];
     let pgtab: &mut [PageTableEntry; mmu::NPTENTRIES];
     if *pde & mmu::PteFlags::PRESENT.bits() != 0 {
@@ -98,10 +114,7 @@ fn walkpgdir(
     Some(&mut pgtab[mmu::ptx(va)])
 }
 
-fn walkpgdir_lookup(
-    pgdir: &[PageDirEntry; mmu::NPDENTRIES],
-    va: vaddr_pg,
-) -> Option<&PageTableEntry> {
+fn walkpgdir_lookup(pgdir: &PageDir, va: vaddr_pg) -> Option<&PageTableEntry> {
     let pde = pgdir[mmu::pdx(va)];
     if pde & mmu::PteFlags::PRESENT.bits() != 0 {
         let pgtab = unsafe {
@@ -117,18 +130,13 @@ fn walkpgdir_lookup(
 // Create PTEs for virtual addresses starting at va that refer to
 // physical addresses starting at pa. va and size might not
 // be page-aligned.
-fn mappages(
-    pgdir: &mut [PageDirEntry; mmu::NPDENTRIES],
+pub fn mappages(
+    pgdir: &mut PageDir,
     va: vaddr,
     size: usize,
     mut pa: paddr_pg,
     perm: mmu::PteFlags,
 ) -> Option<()> {
-    println!(
-        "mappages: virt: {}, start: {}, size: 0x{:08X}",
-        va, pa, size
-    );
-
     let mut a = mmu::page_rounddown(va);
     let last = mmu::page_rounddown(va.next(size - 1));
     loop {
@@ -182,15 +190,13 @@ lazy_static! {
 }
 
 // Set up kernel part of a page table.
-fn setupkvm() -> Option<&'static [PageDirEntry; mmu::NPDENTRIES]> {
+pub fn setupkvm() -> Option<&'static mut PageDir> {
     let pgdir = unsafe {
-        let page = kalloc::kalloc().unwrap();
-        let tmp = page.as_mut_ptr() as *mut [PageTableEntry; mmu::NPTENTRIES];
+        let page = kalloc::kalloc()?;
+        let tmp = page.as_mut_ptr() as *mut PageDir;
         tmp.as_mut().unwrap()
     };
-    utils::fill(pgdir, 0x00000000);
-
-    println!("setupkvm: pgdir = {:p}", pgdir.as_ptr());
+    utils::fill(&mut pgdir[..], 0x00000000);
 
     if p2v_raw(PHYSTOP) > DEVSPACE {
         panic!("PHYSTOP too hight");
@@ -206,7 +212,7 @@ fn setupkvm() -> Option<&'static [PageDirEntry; mmu::NPDENTRIES]> {
         )
         .is_none()
         {
-            freevm(Some(pgdir));
+            freevm(pgdir);
             return None;
         }
     }
@@ -272,16 +278,135 @@ pub fn kvmalloc() {
 
 // Switch h/w page table register to the kernel-only page table,
 // for when no process is running.
-fn switchkvm() {
+pub fn switchkvm() {
     let p = v2p(vaddr::from_ptr(kpgdir.unwrap().as_ptr()).unwrap());
     x86::lcr3(p.as_raw()); // switch to the kernel page table
 }
 
+// Switch TSS and h/w page table to correspond to process p.
+pub fn switchuvm(p: &proc::proc) {
+    if p.kstack.is_null() {
+        panic!("switchuvm: no kstack");
+    }
+    let pgdir: &PageDir = match &p.pgdir {
+        Some(pgdir) => pgdir,
+        None => panic!("switchuvm: no pgdir"),
+    };
+
+    pushcli();
+    {
+        use mmu::seg::*;
+        let c = proc::mycpu();
+        c.gdt[TSS] = mmu::SegDesc::new16(
+            STS_T32A,
+            &c.ts as *const mmu::taskstate as u32,
+            (core::mem::size_of::<mmu::taskstate>() - 1) as u32,
+            0,
+        );
+        c.gdt[TSS].set_s(false);
+        c.ts.ss0 = (KDATA << 3) as u16;
+        c.ts.esp0 = (p.kstack as usize + param::KSTACKSIZE) as u32;
+        // setting IOPL=0 in eflags *and* iomb beyond the tss segment limit
+        // forbids I/O instructions (e.g., inb and outb) from user space
+        c.ts.iomb = 0xFFFF;
+        x86::ltr((TSS << 3) as u16);
+    }
+    let pgdir = v2p(vaddr::from_ptr(pgdir as *const PageDir).unwrap());
+    x86::lcr3(pgdir.as_raw()); // switch to process's address space
+    popcli();
+}
+
+// Load the initcode into address 0 of pgdir.
+// sz must be less than a page.
+pub fn inituvm(pgdir: &mut PageDir, init: &[u8]) {
+    if init.len() >= mmu::PGSIZE {
+        panic!("inituvm: more than a page");
+    }
+    let mem = kalloc::kalloc().expect("inituvm: out of memory");
+    utils::fill(&mut mem[..], 0);
+    let pa = v2p(vaddr_pg::from_ptr(mem.as_ptr()).unwrap());
+    mappages(
+        pgdir,
+        vaddr_raw(0),
+        mmu::PGSIZE,
+        pa,
+        mmu::PteFlags::WRITABLE | mmu::PteFlags::USER,
+    )
+    .expect("inituvm: out of memory");
+    mem[..init.len()].copy_from_slice(init);
+}
+
+// Load a program segment into pgdir.  addr must be page-aligned
+// and the pages from addr to addr+sz must already be mapped.
+pub fn loaduvm(
+    pgdir: &mut PageDir,
+    addr: usize,
+    ip: &mut InodeGuard,
+    offset: usize,
+    sz: usize,
+) -> Result<(), &'static str> {
+    if addr % mmu::PGSIZE != 0 {
+        panic!("loaduvm: addr must be page aligned");
+    }
+    let mut i = 0;
+    while i < sz {
+        let va = vaddr_pg::from_raw(addr + i).unwrap();
+        let pte = walkpgdir_lookup(pgdir, va).expect("loaduvm: address should exist");
+        let pa = mmu::pte_addr(*pte);
+        let n = core::cmp::min(sz - i, mmu::PGSIZE);
+        let dst = unsafe { core::slice::from_raw_parts_mut(p2v(pa).as_mut_ptr::<u8>(), n) };
+        if ip.readi(dst, offset + i)? != n {
+            return Err("loaduvm: short read");
+        }
+        i += mmu::PGSIZE;
+    }
+    Ok(())
+}
+
+// Allocate page tables and physical memory to grow process from old_sz to
+// new_sz, which need not be page aligned.  The new pages are mapped
+// with perm.  Returns new size or None on error.
+pub fn allocuvm(
+    pgdir: &mut PageDir,
+    old_sz: usize,
+    new_sz: usize,
+    perm: mmu::PteFlags,
+) -> Option<usize> {
+    if new_sz >= KERNBASE {
+        return None;
+    }
+    if new_sz < old_sz {
+        return Some(old_sz);
+    }
+
+    let mut a = mmu::page_roundup(vaddr_raw(old_sz));
+    while a.as_raw() < new_sz {
+        let mem = match kalloc::kalloc() {
+            Some(mem) => mem,
+            None => {
+                println!("allocuvm out of memory");
+                deallocuvm(pgdir, new_sz, old_sz);
+                return None;
+            }
+        };
+        utils::fill(&mut mem[..], 0);
+        let pa = v2p(vaddr_pg::from_ptr(mem.as_ptr()).unwrap());
+        if mappages(pgdir, a.into(), mmu::PGSIZE, pa, perm).is_none() {
+            println!("allocuvm out of memory (2)");
+            deallocuvm(pgdir, new_sz, old_sz);
+            kalloc::kfree(mem);
+            return None;
+        }
+        a.increase(1);
+    }
+    Some(new_sz)
+}
+
 // Deallocate user pages to bring the process size from old_sz to
 // new_sz.  old_sz and new_sz need not be page-aligned, nor does new_sz
 // need to be less than old_sz.  old_sz can be larger than the actual
 // process size.  Returns the new process size.
-fn deallocuvm(pgdir: &mut [PageDirEntry; mmu::NPDENTRIES], old_sz: usize, new_sz: usize) -> usize {
+pub fn deallocuvm(pgdir: &mut PageDir, old_sz: usize, new_sz: usize) -> usize {
     if new_sz >= old_sz {
         return old_sz;
     }
@@ -311,18 +436,188 @@ fn deallocuvm(pgdir: &mut [PageDirEntry; mmu::NPDENTRIES], old_sz: usize, new_sz
 
 // Free a page table and all the physical memory pages
 // in the user part.
-fn freevm(pgdir: Option<&mut [PageDirEntry; mmu::NPDENTRIES]>) {
-    if pgdir.is_none() {
-        panic!("freevm: no pgdir");
-    }
-    let pgdir = pgdir.unwrap();
+pub fn freevm(pgdir: &'static mut PageDir) {
     deallocuvm(pgdir, KERNBASE, 0);
-    for dent in pgdir.into_iter() {
+    for dent in pgdir.iter() {
         if dent & mmu::PteFlags::PRESENT.bits() != 0 {
             let table_ptr: *mut mmu::Page = p2v(mmu::pte_addr(*dent)).as_mut_ptr();
             kalloc::kfree(unsafe { table_ptr.as_mut().unwrap() });
         }
     }
-    let ptr = pgdir.as_mut_ptr() as *mut mmu::Page;
+    let ptr = pgdir as *mut PageDir as *mut mmu::Page;
     kalloc::kfree(unsafe { ptr.as_mut().unwrap() });
 }
+
+// Clear PTE_U on a page. Used to create an inaccessible
+// page beneath the user stack.
+pub fn clearpteu(pgdir: &mut PageDir, uva: usize) {
+    let va = mmu::page_rounddown(vaddr_raw(uva));
+    let pte = walkpgdir(pgdir, va, false).expect("clearpteu");
+    *pte &= !mmu::PteFlags::USER.bits();
+}
+
+// Given a parent process's page table, create a copy
+// of it for a child.  The pages themselves are shared:
+// writable pages become read-only and COW in both page
+// tables, and are copied on the first write (see cowfault).
+pub fn copyuvm(pgdir: &mut PageDir, sz: usize) -> Option<&'static mut PageDir> {
+    let d = setupkvm()?;
+    if copyrange(pgdir, d, 0, sz, false).is_none() {
+        freevm(d);
+        return None;
+    }
+    Some(d)
+}
+
+// Map the present user pages of pgdir in [start, end) into d as well.
+// If shared, both page tables keep writing to the same pages;
+// otherwise writable pages are made copy-on-write.
+// start must be page aligned.
+pub fn copyrange(
+    pgdir: &mut PageDir,
+    d: &mut PageDir,
+    start: usize,
+    end: usize,
+    shared: bool,
+) -> Option<()> {
+    let mut a = start;
+    while a < end {
+        let va = vaddr_pg::from_raw(a).unwrap();
+        a += mmu::PGSIZE;
+        let pte = match walkpgdir(pgdir, va, false) {
+            Some(pte) if *pte & mmu::PteFlags::PRESENT.bits() != 0 => pte,
+            _ => continue, // not touched yet; the child faults it in itself
+        };
+        if !shared && *pte & mmu::PteFlags::WRITABLE.bits() != 0 {
+            *pte = (*pte & !mmu::PteFlags::WRITABLE.bits()) | mmu::PteFlags::COW.bits();
+        }
+        let pa = mmu::pte_addr(*pte);
+        let flags = mmu::pte_flags(*pte);
+        if kalloc::incref(pa).is_none() {
+            flushtlb();
+            return None;
+        }
+        if mappages(d, va.into(), mmu::PGSIZE, pa, flags).is_none() {
+            kalloc::kfree(unsafe { &mut *p2v(pa).as_mut_ptr::<mmu::Page>() });
+            flushtlb();
+            return None;
+        }
+    }
+    // The parent's writable pages may just have become read-only.
+    flushtlb();
+    Some(())
+}
+
+// Handle a write fault at user address va on a copy-on-write page.
+// Gives pgdir its own writable copy of the page, or just makes
+// the page writable again if nobody else shares it any more.
+pub fn cowfault(pgdir: &mut PageDir, va: usize) -> Result<(), &'static str> {
+    if va >= KERNBASE {
+        return Err("cowfault: kernel address");
+    }
+    let va = mmu::page_rounddown(vaddr_raw(va));
+    let pte = walkpgdir(pgdir, va, false).ok_or("cowfault: no page table")?;
+    let need = mmu::PteFlags::PRESENT | mmu::PteFlags::USER | mmu::PteFlags::COW;
+    if !mmu::pte_flags(*pte).contains(need) {
+        return Err("cowfault: not a copy-on-write page");
+    }
+
+    let pa = mmu::pte_addr(*pte);
+    let flags = (mmu::pte_flags(*pte) - mmu::PteFlags::COW) | mmu::PteFlags::WRITABLE;
+    if kalloc::refcount(pa) > 1 {
+        let mem = kalloc::kalloc().ok_or("cowfault: out of memory")?;
+        mem.copy_from_slice(unsafe { &*p2v(pa).as_ptr::<mmu::Page>() });
+        let mem_pa = v2p(vaddr_pg::from_ptr(mem.as_ptr()).unwrap());
+        *pte = (mem_pa.as_raw() as u32) | flags.bits();
+        let old: *mut mmu::Page = p2v(pa).as_mut_ptr();
+        kalloc::kfree(unsafe { old.as_mut().unwrap() });
+    } else {
+        *pte = (pa.as_raw() as u32) | flags.bits();
+    }
+    flushtlb();
+    Ok(())
+}
+
+// Map a zeroed page at user address va, the first
+// touch of a lazily allocated page.
+pub fn lazyalloc(pgdir: &mut PageDir, va: usize) -> Result<(), &'static str> {
+    if va >= KERNBASE {
+        return Err("lazyalloc: kernel address");
+    }
+    let a = mmu::page_rounddown(vaddr_raw(va));
+    let mem = kalloc::kalloc().ok_or("lazyalloc: out of memory")?;
+    utils::fill(&mut mem[..], 0);
+    let pa = v2p(vaddr_pg::from_ptr(mem.as_ptr()).unwrap());
+    let perm = mmu::PteFlags::WRITABLE | mmu::PteFlags::USER;
+    if mappages(pgdir, a.into(), mmu::PGSIZE, pa, perm).is_none() {
+        kalloc::kfree(mem);
+        return Err("lazyalloc: out of memory");
+    }
+    Ok(())
+}
+
+// Fault in the user pages of pgdir in [start, end) ahead of a
+// kernel access, as the page fault handler would: missing pages
+// are allocated zeroed, and copy-on-write pages are copied if
+// write is set.  Fails, rather than leaving the kernel to fault,
+// if memory runs out or the access isn't allowed.
+pub fn prefault(
+    pgdir: &mut PageDir,
+    start: usize,
+    end: usize,
+    write: bool,
+) -> Result<(), &'static str> {
+    let mut a = mmu::page_rounddown(vaddr_raw(start)).as_raw();
+    while a < end {
+        let flags = walkpgdir(pgdir, vaddr_pg::from_raw(a).unwrap(), false)
+            .map_or(mmu::PteFlags::empty(), |pte| mmu::pte_flags(*pte));
+        if !flags.contains(mmu::PteFlags::PRESENT) {
+            lazyalloc(pgdir, a)?;
+        } else if !flags.contains(mmu::PteFlags::USER) {
+            return Err("user address out of range"); // the stack guard page
+        } else if write && !flags.contains(mmu::PteFlags::WRITABLE) {
+            cowfault(pgdir, a)?;
+        }
+        a += mmu::PGSIZE;
+    }
+    Ok(())
+}
+
+// Discard stale TLB entries after PTEs of the
+// current page table have changed.
+fn flushtlb() {
+    x86::lcr3(x86::rcr3());
+}
+
+// Map user virtual address to kernel address.
+pub fn uva2ka(pgdir: &PageDir, uva: usize) -> Option<*mut u8> {
+    let va = mmu::page_rounddown(vaddr_raw(uva));
+    let pte = *walkpgdir_lookup(pgdir, va)?;
+    if pte & mmu::PteFlags::PRESENT.bits() == 0 || pte & mmu::PteFlags::USER.bits() == 0 {
+        return None;
+    }
+    Some(p2v(mmu::pte_addr(pte)).as_mut_ptr())
+}
+
+// Copy src to user address va in page table pgdir.
+// Most useful when pgdir is not the current page table.
+// uva2ka ensures this only works for PTE_U pages.
+// Copy-on-write pages are copied before being written.
+pub fn copyout(pgdir: &mut PageDir, mut va: usize, mut src: &[u8]) -> Result<(), &'static str> {
+    while !src.is_empty() {
+        let va0 = mmu::page_rounddown(vaddr_raw(va)).as_raw();
+        let cow = walkpgdir_lookup(pgdir, vaddr_pg::from_raw(va0).unwrap())
+            .map_or(false, |pte| *pte & mmu::PteFlags::COW.bits() != 0);
+        if cow {
+            cowfault(pgdir, va0)?;
+        }
+        let pa0 = uva2ka(pgdir, va0).ok_or("copyout: bad user address")?;
+        let n = core::cmp::min(mmu::PGSIZE - (va - va0), src.len());
+        unsafe {
+            core::ptr::copy(src.as_ptr(), pa0.add(va - va0), n);
+        }
+        src = &src[n..];
+        va = va0 + mmu::PGSIZE;
+    }
+    Ok(())
+}
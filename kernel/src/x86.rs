@@ -26,39 +26,41 @@ bitflags! {
 
 // Layout of the trap frame built on the stack by the
 // hardware and by trapasm.S, and passed to trap().
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
 pub struct trapframe {
     // registers as pushed by pusha
-    edi: u32,
-    esi: u32,
-    ebp: u32,
-    oesp: u32,
-    ebx: u32,
-    edx: u32,
-    ecx: u32,
-    eax: u32,
+    pub edi: u32,
+    pub esi: u32,
+    pub ebp: u32,
+    pub oesp: u32,
+    pub ebx: u32,
+    pub edx: u32,
+    pub ecx: u32,
+    pub eax: u32,
 
     // rest of trap frame
-    gs: u16,
-    padding1: u16,
-    fs: u16,
-    padding2: u16,
-    es: u16,
-    padding3: u16,
-    ds: u16,
-    padding4: u16,
-    trapno: u32,
+    pub gs: u16,
+    pub padding1: u16,
+    pub fs: u16,
+    pub padding2: u16,
+    pub es: u16,
+    pub padding3: u16,
+    pub ds: u16,
+    pub padding4: u16,
+    pub trapno: u32,
 
     // bellow here defined by x86 hardware
-    err: u32,
-    epi: u32,
-    cs: u16,
-    padding5: u16,
-    eflags: u32,
+    pub err: u32,
+    pub eip: u32,
+    pub cs: u16,
+    pub padding5: u16,
+    pub eflags: u32,
 
     // below here only when crossing rings, such as from user to kernel
-    esp: u32,
-    ss: u16,
-    padding6: u16,
+    pub esp: u32,
+    pub ss: u16,
+    pub padding6: u16,
 }
 
 #[inline]
@@ -79,6 +81,13 @@ pub fn cli() {
     }
 }
 
+#[inline]
+pub fn sti() {
+    unsafe {
+        asm!("sti"::::"volatile");
+    }
+}
+
 // read a byte from the port
 #[inline]
 pub fn inb(port: u16) -> u8 {
@@ -181,6 +190,16 @@ pub fn nop() {
     }
 }
 
+// Read the time-stamp counter.
+#[inline]
+pub fn rdtsc() -> u64 {
+    let tsc: u64;
+    unsafe {
+        asm!("rdtsc" : "=A" (tsc) ::: "volatile");
+    }
+    tsc
+}
+
 #[inline]
 pub fn xchg(addr: &mut usize, new_val: usize) -> usize {
     let mut result;
@@ -207,6 +226,19 @@ pub fn rcr2() -> usize {
     val
 }
 
+#[inline]
+pub fn rcr3() -> usize {
+    let mut val;
+    unsafe {
+        asm!("movl %cr3, $0"
+                : "=r" (val)
+                :
+                :
+                : "volatile");
+    }
+    val
+}
+
 #[inline]
 pub fn lcr3(val: usize) {
     unsafe {
@@ -218,6 +250,34 @@ pub fn lcr3(val: usize) {
     }
 }
 
+#[inline]
+pub fn ltr(sel: u16) {
+    unsafe {
+        asm!("ltr $0"
+                :
+                : "r" (sel)
+                :
+                : "volatile");
+    }
+}
+
+#[inline]
+pub fn lidt(p: *const crate::mmu::GateDesc, size: u16) {
+    let pd = [
+        size - 1,
+        ((p as usize) & 0xffff) as u16,
+        (((p as usize) >> 16) & 0xffff) as u16,
+    ];
+    let ptr = &pd as *const u16;
+    unsafe {
+        asm!("lidt ($0)"
+                :
+                : "r" (ptr)
+                :
+                : "volatile");
+    }
+}
+
 #[inline]
 pub fn lgdt(p: *mut crate::mmu::SegDesc, size: u16) {
     let pd = [